@@ -0,0 +1,132 @@
+// Which peers this node talks to. The allowlist and blocklist come from the
+// config; BAN adds to a separate list that is saved in the data directory so
+// bans survive restarts. Refused peers are banned in the swarm (their
+// connections are closed) and blacklisted in gossipsub.
+use crate::Result;
+use libp2p::PeerId;
+use log::error;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const BANS_FILE: &str = "banned_peers.json";
+
+#[derive(Debug, Default)]
+pub struct Access {
+    // Empty means every peer not blocked is allowed
+    allowed: BTreeSet<PeerId>,
+    blocked: BTreeSet<PeerId>,
+    banned: BTreeSet<PeerId>,
+    // Where bans are saved; None (ephemeral nodes) keeps them in memory
+    bans_path: Option<PathBuf>,
+}
+
+impl Access {
+    // Loads the saved bans from data_dir, if there is one
+    pub fn new(allow: &[PeerId], block: &[PeerId], data_dir: Option<&Path>) -> Result<Self> {
+        let mut access = Access {
+            allowed: allow.iter().copied().collect(),
+            blocked: block.iter().copied().collect(),
+            ..Default::default()
+        };
+        if let Some(data_dir) = data_dir {
+            let path = data_dir.join(BANS_FILE);
+            if path.exists() {
+                access.banned = serde_json::from_slice::<Vec<String>>(&fs::read(&path)?)?
+                    .iter()
+                    .map(|p| p.parse::<PeerId>())
+                    .collect::<std::result::Result<BTreeSet<_>, _>>()
+                    .map_err(|e| format!("invalid peer id in {}: {}", path.display(), e))?;
+            }
+            access.bans_path = Some(path);
+        }
+        Ok(access)
+    }
+
+    pub fn is_allowed(&self, peer: &PeerId) -> bool {
+        let allowed = self.allowed.is_empty() || self.allowed.contains(peer);
+        allowed && !self.blocked.contains(peer) && !self.banned.contains(peer)
+    }
+
+    // Peers to refuse right away: the blocklist and the saved bans
+    pub fn blocked(&self) -> Vec<PeerId> {
+        self.banned.union(&self.blocked).copied().collect()
+    }
+
+    pub fn banned(&self) -> Vec<PeerId> {
+        self.banned.iter().copied().collect()
+    }
+
+    // Returns false if the peer was already banned
+    pub fn ban(&mut self, peer: PeerId) -> bool {
+        let added = self.banned.insert(peer);
+        if added {
+            self.save();
+        }
+        added
+    }
+
+    // Returns false if the peer wasn't banned
+    pub fn unban(&mut self, peer: &PeerId) -> bool {
+        let removed = self.banned.remove(peer);
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.bans_path {
+            if let Err(e) = write(path, &self.banned) {
+                error!("error saving banned peers to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn write(path: &Path, banned: &BTreeSet<PeerId>) -> Result<()> {
+    let banned = banned.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&banned)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist_and_blocklist() {
+        let (friend, stranger, foe) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let access = Access::new(&[friend, foe], &[foe], None).unwrap();
+        assert!(access.is_allowed(&friend));
+        assert!(!access.is_allowed(&stranger));
+        assert!(!access.is_allowed(&foe));
+        assert_eq!(access.blocked(), vec![foe]);
+
+        let open = Access::new(&[], &[foe], None).unwrap();
+        assert!(open.is_allowed(&stranger));
+        assert!(!open.is_allowed(&foe));
+    }
+
+    #[test]
+    fn bans_survive_restarts() {
+        let dir = std::env::temp_dir().join(format!("peercache-access-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let (peer, other) = (PeerId::random(), PeerId::random());
+
+        let mut access = Access::new(&[], &[], Some(&dir)).unwrap();
+        assert!(access.ban(peer));
+        assert!(!access.ban(peer));
+        assert!(access.ban(other));
+        assert!(access.unban(&other));
+        assert!(!access.unban(&other));
+
+        let access = Access::new(&[], &[], Some(&dir)).unwrap();
+        assert_eq!(access.banned(), vec![peer]);
+        assert!(!access.is_allowed(&peer));
+        assert!(access.is_allowed(&other));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,650 @@
+// The libp2p behaviour of a node: gossipsub for topology announcements, mDNS
+// and Kademlia for discovery, and request-response for NFT queries.
+use crate::access::Access;
+use crate::config;
+use crate::events::{self, Event};
+use crate::follow::{self, Follow, Following};
+use crate::latency::PeerLatency;
+use crate::local_only::LocalOnly;
+use crate::namespace::Namespace;
+use crate::node::NodeConfig;
+use crate::protocol::{self, NFTQueryCodec, NFTQueryProtocol};
+use crate::query::{self, PendingQuery, QueryTracker, ResponseStatus};
+use crate::responder::Job;
+use crate::selection::Selector;
+use crate::slowlog::{self, SlowQuery};
+use crate::store::NftStore;
+use crate::topology::TopologyAnnouncement;
+use crate::trash::Trash;
+use crate::version::{self, VersionSkew};
+use crate::{
+    report_command_error, ListMode, ListRequest, ListResponse, NFTInfo, NFTInfoList, Result,
+    DHT_PROTOCOL_NAME, IDENTIFY_PROTOCOL_VERSION,
+};
+use libp2p::{
+    gossipsub::{Gossipsub, GossipsubEvent, MessageAuthenticity},
+    identify::{Identify, IdentifyConfig, IdentifyEvent, IdentifyInfo},
+    identity::Keypair,
+    kad::{
+        record::{store::MemoryStore, Key},
+        Kademlia, KademliaConfig, KademliaEvent, QueryResult, Quorum,
+    },
+    mdns::{Mdns, MdnsConfig, MdnsEvent},
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    request_response::{
+        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{toggle::Toggle, NetworkBehaviourEventProcess},
+    NetworkBehaviour, PeerId,
+};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::Instant;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+// A response waiting to be written back on the stream its request arrived on
+pub type PendingResponse = (ResponseChannel<ListResponse>, ListResponse);
+
+#[derive(NetworkBehaviour)]
+pub struct NFTInfoBehaviour {
+    pub gossipsub: Gossipsub,
+    pub mdns: Toggle<Mdns>,
+    pub kademlia: Kademlia<MemoryStore>,
+    pub request_response: RequestResponse<NFTQueryCodec>,
+    pub identify: Identify,
+    pub ping: Ping,
+    // mDNS peers waiting to be dialed so gossipsub can add them to its mesh
+    #[behaviour(ignore)]
+    pub pending_dials: HashSet<PeerId>,
+    // Recent ping round-trip times (PEER STATS)
+    #[behaviour(ignore)]
+    pub latency: HashMap<PeerId, PeerLatency>,
+    // What connected peers told us about themselves (SHOW PEER)
+    #[behaviour(ignore)]
+    pub peer_info: HashMap<PeerId, IdentifyInfo>,
+    // Whether the advisory about newer peer versions was logged already
+    #[behaviour(ignore)]
+    pub version_advised: bool,
+    #[behaviour(ignore)]
+    pub store: NftStore,
+    // Accepted inbound requests, answered by the responder workers
+    #[behaviour(ignore)]
+    pub jobs: mpsc::Sender<Job>,
+    #[behaviour(ignore)]
+    pub topology: HashMap<String, (Instant, TopologyAnnouncement)>,
+    #[behaviour(ignore)]
+    pub queries: QueryTracker,
+    // The last query that got no complete answer (the query subcommand fails on it)
+    #[behaviour(ignore)]
+    pub query_failure: Option<String>,
+    // Picks the hosts of collection queries from how peers answered so far
+    #[behaviour(ignore)]
+    pub selection: Selector,
+    // Inbound requests accepted and not answered yet
+    #[behaviour(ignore)]
+    pub inbound_requests: HashSet<RequestId>,
+    #[behaviour(ignore)]
+    pub resources: config::ResourceConfig,
+    #[behaviour(ignore)]
+    pub following: Following,
+    // Deleted records that can still be restored
+    #[behaviour(ignore)]
+    pub trash: Trash,
+    // The node's identity, used to sign burns and genesis files
+    #[behaviour(ignore)]
+    pub keys: Keypair,
+    #[behaviour(ignore)]
+    pub namespace: Namespace,
+    #[behaviour(ignore)]
+    pub local_only: LocalOnly,
+    #[behaviour(ignore)]
+    pub access: Access,
+    // Mirror shareable records into the Kademlia DHT
+    #[behaviour(ignore)]
+    pub dht_records: bool,
+    // Named sets of peers that queries can be restricted to with @<group>
+    #[behaviour(ignore)]
+    pub groups: BTreeMap<String, BTreeSet<PeerId>>,
+}
+
+impl NFTInfoBehaviour {
+    pub async fn new(
+        config: &NodeConfig,
+        keys: Keypair,
+        namespace: Namespace,
+        store: NftStore,
+        jobs: mpsc::Sender<Job>,
+        access: Access,
+        local_only: LocalOnly,
+    ) -> Result<Self> {
+        let peer_id = PeerId::from(keys.public());
+        let mut kademlia_config = KademliaConfig::default();
+        kademlia_config.set_protocol_name(namespace.protocol(DHT_PROTOCOL_NAME));
+        // Queries give up on their own deadline, which can be longer than the default 10s
+        let mut request_response_config = RequestResponseConfig::default();
+        request_response_config.set_request_timeout(query::MAX_TIMEOUT);
+        Ok(NFTInfoBehaviour {
+            gossipsub: Gossipsub::new(
+                MessageAuthenticity::Signed(keys.clone()),
+                config.gossip.build()?,
+            )?,
+            // Without mDNS, peers are only found through BOOTSTRAP and the DHT
+            mdns: match config.mdns {
+                true => Some(Mdns::new(MdnsConfig::default()).await?),
+                false => None,
+            }
+            .into(),
+            kademlia: Kademlia::with_config(peer_id, MemoryStore::new(peer_id), kademlia_config),
+            request_response: RequestResponse::new(
+                NFTQueryCodec,
+                std::iter::once((
+                    NFTQueryProtocol(namespace.protocol(protocol::PROTOCOL_NAME)),
+                    ProtocolSupport::Full,
+                )),
+                request_response_config,
+            ),
+            identify: Identify::new(
+                IdentifyConfig::new(
+                    String::from_utf8_lossy(&namespace.protocol(IDENTIFY_PROTOCOL_VERSION))
+                        .into_owned(),
+                    keys.public(),
+                )
+                .with_agent_version(version::agent_version()),
+            ),
+            ping: Ping::new(PingConfig::new()),
+            latency: HashMap::new(),
+            peer_info: HashMap::new(),
+            version_advised: false,
+            pending_dials: HashSet::new(),
+            store,
+            jobs,
+            topology: HashMap::new(),
+            queries: QueryTracker::new(config.timeouts.clone()),
+            query_failure: None,
+            selection: Selector::new(config.selection.clone()),
+            inbound_requests: HashSet::new(),
+            resources: config.resources.clone(),
+            following: Following::default(),
+            trash: Trash::default(),
+            keys,
+            namespace,
+            local_only,
+            access,
+            dht_records: config.dht_records,
+            groups: config.groups.clone(),
+        })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from(self.keys.public())
+    }
+
+    // When the next query expires or hedge is due
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match (self.queries.next_deadline(), self.selection.next_hedge()) {
+            (Some(query), Some(hedge)) => Some(query.min(hedge)),
+            (query, hedge) => query.or(hedge),
+        }
+    }
+
+    // Asks the next-best host of queries that weren't answered within the hedging delay
+    pub fn send_hedges(&mut self, now: Instant) {
+        for hedge in self.selection.due_hedges(now) {
+            if !self.queries.is_pending(hedge.query_id) {
+                continue;
+            }
+            let request_id = self.request_response.send_request(
+                &hedge.backup,
+                ListRequest {
+                    mode: hedge.mode,
+                    request_id: hedge.query_id,
+                },
+            );
+            self.queries.add_request(hedge.query_id, request_id);
+            self.selection
+                .hedge_sent(hedge.first, request_id, hedge.backup);
+            debug!(
+                "Query #{} not answered yet, also asking {}",
+                hedge.query_id, hedge.backup
+            );
+        }
+    }
+
+    pub fn finish_query(&mut self, query: PendingQuery, timed_out: bool) {
+        if let Some(follow) = &query.mirror {
+            self.mirror_followed(follow, &query);
+            return;
+        }
+        report_query_finished(&query, timed_out || query.partial);
+        if timed_out || query.partial || query.responders.is_empty() {
+            self.query_failure = Some(format!(
+                "{} of {} peers responded to query #{}",
+                query.responders.len(),
+                query.expected,
+                query.id
+            ));
+        }
+        slowlog::record(SlowQuery {
+            source: "network".to_owned(),
+            filter: describe_query(&query.mode),
+            rows_scanned: query.records.len(),
+            peers: query.expected,
+            duration: query.started.elapsed(),
+        });
+        let (collection_name, item_id) = match (&query.mode, query.dht_item) {
+            (ListMode::Collection(collection_name), Some(item_id)) => (collection_name, item_id),
+            _ => return,
+        };
+        let found = query
+            .records
+            .iter()
+            .any(|r| &r.collection_name == collection_name && r.item_id == item_id);
+        if !found && self.dht_records {
+            info!(
+                "No peer returned {}|{}, looking it up in the DHT",
+                collection_name, item_id
+            );
+            self.kademlia
+                .get_record(&dht_key(collection_name, item_id), Quorum::One);
+        }
+    }
+
+    pub fn version_skew(&self) -> VersionSkew {
+        VersionSkew::new(
+            &version::agent_version(),
+            &String::from_utf8_lossy(&self.namespace.protocol(IDENTIFY_PROTOCOL_VERSION)),
+            self.peer_info.values(),
+        )
+    }
+
+    // Drops what we learned about a peer once its last connection is gone
+    pub fn forget_peer(&mut self, peer: &PeerId) {
+        self.latency.remove(peer);
+        self.peer_info.remove(peer);
+    }
+
+    // Sync queries run in the background, so only changes are reported
+    fn mirror_followed(&mut self, follow: &Follow, query: &PendingQuery) {
+        // Unfollowed while the query was running
+        if !self.following.contains(follow) {
+            return;
+        }
+        let changed = follow::mirror(&self.store, &self.peer_id(), follow, &query.records);
+        for collection_name in changed {
+            info!("Synced {} from {}", collection_name, follow);
+            if self.local_only.contains(&collection_name) {
+                continue;
+            }
+            // Serve the mirrored collection to others too
+            let topic = self.namespace.collection_topic(&collection_name);
+            if let Err(e) = self.gossipsub.subscribe(&topic) {
+                error!("error subscribing to {}: {:?}", collection_name, e);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<GossipsubEvent> for NFTInfoBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        match event {
+            GossipsubEvent::Message { message: msg, .. } => {
+                // Strict validation means every message is signed by its author
+                let source = match &msg.source {
+                    Some(source) if self.access.is_allowed(source) => source.to_string(),
+                    _ => return,
+                };
+                match serde_json::from_slice::<TopologyAnnouncement>(&msg.data) {
+                    // A peer can only announce its own view of the network
+                    Ok(announcement) if announcement.peer == source => {
+                        self.topology.insert(source, (Instant::now(), announcement));
+                    }
+                    Ok(announcement) => debug!(
+                        "dropping topology of {} announced by {}",
+                        announcement.peer, source
+                    ),
+                    Err(_) => (),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<ListRequest, ListResponse>>
+    for NFTInfoBehaviour
+{
+    fn inject_event(&mut self, event: RequestResponseEvent<ListRequest, ListResponse>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request_id,
+                        request: req,
+                        channel,
+                    },
+            } => {
+                events::emit(Event::Request {
+                    source: peer.to_string(),
+                    mode: &req.mode,
+                });
+                // Dropping the channel makes the requester see the request fail
+                if self.inbound_requests.len() >= self.resources.max_inbound_requests {
+                    error!(
+                        "refusing request from {}: {} requests are already being answered",
+                        peer,
+                        self.inbound_requests.len()
+                    );
+                    return;
+                }
+                info!("Received req: {:?} from {:?}", req, peer);
+                let job = Job {
+                    channel,
+                    mode: req.mode,
+                    peer,
+                    request_id: req.request_id,
+                };
+                match self.jobs.try_send(job) {
+                    Ok(()) => {
+                        self.inbound_requests.insert(request_id);
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        error!("refusing request from {}: the request queue is full", peer)
+                    }
+                    Err(TrySendError::Closed(_)) => error!("request workers have stopped"),
+                }
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response: resp,
+                    },
+            } => {
+                self.selection.answered(request_id);
+                if self.selection.lost(request_id) {
+                    debug!("{} answered after its hedge rival, ignoring it", peer);
+                    return;
+                }
+                // The rival of a hedge isn't needed any more
+                if let Some(rival) = self.selection.won(request_id) {
+                    self.queries.cancel(rival);
+                }
+                let query_id = self.queries.query_id(request_id);
+                // Peers running older versions don't echo the query number
+                let answers = match resp.request_id {
+                    0 => query_id,
+                    id => Some(id),
+                };
+                let status = if query_id.is_some() && answers != query_id {
+                    error!(
+                        "response from {} answers query #{} but was requested for query #{}, ignoring it",
+                        peer,
+                        resp.request_id,
+                        query_id.unwrap_or_default()
+                    );
+                    self.queries.record_failure(request_id)
+                } else {
+                    self.queries.record_response(request_id, peer, &resp.data)
+                };
+                // Responses are merged into one table once the query finishes
+                match (query_id, answers) {
+                    (Some(id), _) => {
+                        debug!("Query #{}: {} records from {}", id, resp.data.len(), peer)
+                    }
+                    (None, Some(id)) => info!(
+                        "[late] {} records from {} for query #{}, which already finished",
+                        resp.data.len(),
+                        peer,
+                        id
+                    ),
+                    (None, None) => info!(
+                        "[late] {} records from {} after their query finished",
+                        resp.data.len(),
+                        peer
+                    ),
+                }
+                events::emit(Event::Response {
+                    query: answers,
+                    source: peer.to_string(),
+                    mode: &resp.mode,
+                    data: &resp.data,
+                    partial: true,
+                });
+                if let ResponseStatus::Complete(query) = status {
+                    self.finish_query(query, false);
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.selection.failed(request_id);
+                if self.selection.lost(request_id) {
+                    debug!("query to {} failed after its hedge rival answered", peer);
+                    return;
+                }
+                error!("query to {} failed: {:?}", peer, error);
+                let status = match error {
+                    OutboundFailure::Timeout => self.queries.record_timeout(request_id),
+                    _ => self.queries.record_failure(request_id),
+                };
+                if let ResponseStatus::Complete(query) = status {
+                    self.finish_query(query, false);
+                }
+            }
+            RequestResponseEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                // Refused requests fail with ResponseOmission and were never counted
+                if self.inbound_requests.remove(&request_id) {
+                    error!("error answering query from {}: {:?}", peer, error);
+                }
+            }
+            RequestResponseEvent::ResponseSent { request_id, .. } => {
+                self.inbound_requests.remove(&request_id);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for NFTInfoBehaviour {
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        match event {
+            IdentifyEvent::Received { peer_id, info } => {
+                debug!("Identified {} as {}", peer_id, info.agent_version);
+                self.peer_info.insert(peer_id, info);
+                // Logged once, when the first peers we meet show this node is outdated
+                if !self.version_advised {
+                    if let Some(advisory) = self.version_skew().advisory() {
+                        warn!("{}", advisory);
+                        self.version_advised = true;
+                    }
+                }
+            }
+            IdentifyEvent::Error { peer_id, error } => {
+                debug!("error identifying {}: {:?}", peer_id, error)
+            }
+            IdentifyEvent::Sent { .. } | IdentifyEvent::Pushed { .. } => (),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for NFTInfoBehaviour {
+    fn inject_event(&mut self, event: PingEvent) {
+        let latency = self.latency.entry(event.peer).or_default();
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => latency.record(rtt),
+            // Answering the peer's ping tells us nothing about the round trip
+            Ok(PingSuccess::Pong) => (),
+            Err(e) => {
+                latency.failures += 1;
+                debug!("ping to {} failed: {:?}", event.peer, e);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for NFTInfoBehaviour {
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(discovered_list) => {
+                for (peer, addr) in discovered_list {
+                    self.kademlia.add_address(&peer, addr);
+                    self.pending_dials.insert(peer);
+                }
+            }
+            // Connections to expired peers time out on their own and gossipsub prunes them
+            MdnsEvent::Expired(_) => (),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for NFTInfoBehaviour {
+    fn inject_event(&mut self, event: KademliaEvent) {
+        match event {
+            // Connect to peers found through the DHT so they can join the gossipsub mesh
+            KademliaEvent::RoutingUpdated { peer, .. } => {
+                self.pending_dials.insert(peer);
+            }
+            KademliaEvent::OutboundQueryCompleted {
+                result: QueryResult::Bootstrap(result),
+                ..
+            } => match result {
+                Ok(ok) if ok.num_remaining == 0 => info!("Kademlia bootstrap complete"),
+                Ok(_) => (),
+                Err(e) => error!("kademlia bootstrap failed: {:?}", e),
+            },
+            KademliaEvent::OutboundQueryCompleted {
+                result: QueryResult::PutRecord(result),
+                ..
+            } => match result {
+                Ok(ok) => info!(
+                    "Stored {} in the DHT",
+                    String::from_utf8_lossy(ok.key.as_ref())
+                ),
+                Err(e) => error!("error storing record in the DHT: {:?}", e),
+            },
+            KademliaEvent::OutboundQueryCompleted {
+                result: QueryResult::GetRecord(result),
+                ..
+            } => match result {
+                Ok(ok) => {
+                    let records = ok
+                        .records
+                        .iter()
+                        .filter_map(|r| serde_json::from_slice::<NFTInfo>(&r.record.value).ok())
+                        // Anyone can store a record under any key
+                        .filter(|r| match r.verify_burn(None) {
+                            Ok(()) => true,
+                            Err(e) => {
+                                error!(
+                                    "ignoring DHT record {}|{}: {}",
+                                    r.collection_name, r.item_id, e
+                                );
+                                false
+                            }
+                        })
+                        .collect::<NFTInfoList>();
+                    info!("[dht] Records:");
+                    records.iter().for_each(|r| info!("{:?}", r));
+                    events::emit(Event::DhtRecords { data: &records });
+                }
+                Err(e) => {
+                    let message = format!(
+                        "{} not found in the DHT: {:?}",
+                        String::from_utf8_lossy(e.key().as_ref()),
+                        e
+                    );
+                    self.query_failure = Some(message.clone());
+                    report_command_error(message);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+pub fn dht_key(collection_name: &str, item_id: u32) -> Key {
+    Key::new(&format!("{}/{}", collection_name, item_id))
+}
+
+pub fn describe_query(mode: &ListMode) -> String {
+    match mode {
+        ListMode::ALL => "GET NFT ALL".to_owned(),
+        ListMode::Collection(collection_name) => format!("GET NFT {}", collection_name),
+        ListMode::Owner(owner) => format!("GET NFT OWNER {}", owner),
+        ListMode::CollectionSince(collection_name, seq) => {
+            format!("GET NFT {} SINCE {}", collection_name, seq)
+        }
+    }
+}
+
+fn report_query_finished(query: &PendingQuery, timed_out: bool) {
+    if timed_out {
+        report_command_error(format!(
+            "Query #{} ({}) timed out after {:?}: {} of {} peers responded with {} records (partial results)",
+            query.id,
+            describe_query(&query.mode),
+            query.timeout,
+            query.responders.len(),
+            query.expected,
+            query.records.len()
+        ));
+    } else {
+        info!(
+            "Query #{} ({}) complete after {:?}: {} peers responded with {} records",
+            query.id,
+            describe_query(&query.mode),
+            query.started.elapsed(),
+            query.responders.len(),
+            query.records.len()
+        );
+    }
+    info!("collection_name | item_id | owner | description | peers");
+    for (record, sources) in query.records.iter().zip(&query.sources) {
+        info!(
+            "{} | {} | {} | {} | {}",
+            record.collection_name,
+            record.item_id,
+            record.owner,
+            record.description,
+            sources
+                .iter()
+                .map(|p| short_peer_id(p))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    info!("({} rows)", query.records.len());
+    if let ListMode::CollectionSince(..) = query.mode {
+        // A follower resumes from here next time
+        if let Some(seq) = query.records.iter().filter_map(|r| r.seq).max() {
+            info!("Highest sequence number: {}", seq);
+        }
+    }
+    events::emit(Event::Final {
+        query: query.id,
+        mode: &query.mode,
+        timed_out,
+        responders: query.responders.len(),
+        expected: query.expected,
+        data: &query.records,
+        sources: query
+            .sources
+            .iter()
+            .map(|peers| peers.iter().map(|p| p.to_string()).collect())
+            .collect(),
+    });
+}
+
+// The tail of a peer id is enough to tell the peers of one query apart
+fn short_peer_id(peer: &PeerId) -> String {
+    let id = peer.to_string();
+    id[id.len().saturating_sub(8)..].to_owned()
+}
@@ -0,0 +1,126 @@
+// Circuit breaker of a peer that keeps failing our queries. After
+// breaker_failures failures in a row the breaker opens and collection queries
+// skip the peer; once the cooldown has passed it is half-open and the next
+// query to the peer is the probe: an answer closes the breaker, a failure
+// opens it again.
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Breaker {
+    // Failures in a row
+    failures: u32,
+    // None while the breaker is closed
+    opened: Option<Instant>,
+    // The probe of a half-open breaker is waiting for an answer
+    probing: bool,
+}
+
+impl Breaker {
+    pub fn state(&self, now: Instant, cooldown: Duration) -> BreakerState {
+        match self.opened {
+            None => BreakerState::Closed,
+            Some(opened) if now < opened + cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    // Whether a query may be sent to the peer
+    pub fn allows(&self, now: Instant, cooldown: Duration) -> bool {
+        match self.state(now, cooldown) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => !self.probing,
+        }
+    }
+
+    pub fn sent(&mut self, now: Instant, cooldown: Duration) {
+        if self.state(now, cooldown) == BreakerState::HalfOpen {
+            self.probing = true;
+        }
+    }
+
+    pub fn success(&mut self) {
+        *self = Breaker::default();
+    }
+
+    // A threshold of 0 never opens the breaker
+    pub fn failure(&mut self, now: Instant, threshold: u32) {
+        self.failures += 1;
+        self.probing = false;
+        // A failed probe opens the breaker again right away
+        if self.opened.is_some() || (threshold > 0 && self.failures >= threshold) {
+            self.opened = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn opens_after_failures_in_a_row() {
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+        breaker.failure(now, 3);
+        breaker.failure(now, 3);
+        // An answer starts the count again
+        breaker.success();
+        breaker.failure(now, 3);
+        breaker.failure(now, 3);
+        assert_eq!(breaker.state(now, COOLDOWN), BreakerState::Closed);
+        breaker.failure(now, 3);
+        assert_eq!(breaker.state(now, COOLDOWN), BreakerState::Open);
+        assert!(!breaker.allows(now, COOLDOWN));
+    }
+
+    #[test]
+    fn probes_once_half_open() {
+        let now = Instant::now();
+        let later = now + COOLDOWN;
+        let mut breaker = Breaker::default();
+        breaker.failure(now, 1);
+        assert_eq!(breaker.state(later, COOLDOWN), BreakerState::HalfOpen);
+        assert!(breaker.allows(later, COOLDOWN));
+        breaker.sent(later, COOLDOWN);
+        assert!(!breaker.allows(later, COOLDOWN));
+
+        // A failed probe opens it again, an answered one closes it
+        breaker.failure(later, 1);
+        assert_eq!(breaker.state(later, COOLDOWN), BreakerState::Open);
+        let after = later + COOLDOWN;
+        breaker.sent(after, COOLDOWN);
+        breaker.success();
+        assert_eq!(breaker.state(after, COOLDOWN), BreakerState::Closed);
+    }
+
+    #[test]
+    fn never_opens_without_a_threshold() {
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+        for _ in 0..10 {
+            breaker.failure(now, 0);
+        }
+        assert!(breaker.allows(now, COOLDOWN));
+    }
+}
@@ -0,0 +1,1546 @@
+// The REPL commands, shared by the CLI and embedders through PeerCacheNode::execute
+use crate::behaviour::{dht_key, NFTInfoBehaviour};
+use crate::error::PeerCacheError;
+use crate::events::{self, Event, Status};
+use crate::follow::{self, Follow};
+use crate::query::MAX_TIMEOUT;
+use crate::slowlog::{self, SlowQuery};
+use crate::store::NftStore;
+use crate::topology::{self, TopologyAnnouncement};
+use crate::{
+    config, genesis, logging, next_seq, report_command_error, set_seq, shareable_nft_info, sql,
+    BurnStatement, ListMode, ListRequest, NFTInfo, Result, TOPOLOGY_TTL,
+};
+use libp2p::{
+    gossipsub::{error::PublishError, MessageId, TopicHash},
+    kad::{record::Record, Quorum},
+    multiaddr::Protocol,
+    swarm::Swarm,
+    Multiaddr, PeerId,
+};
+use log::{debug, error, info, LevelFilter};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+async fn create_new_nft_info(
+    store: &NftStore,
+    local: &PeerId,
+    mut nft_info: NFTInfo,
+) -> Result<NFTInfo> {
+    for attachment in &nft_info.attachments {
+        attachment.validate()?;
+    }
+    let mut local_nft_info = store.write();
+    for link in &nft_info.related {
+        if link.collection_name == nft_info.collection_name && link.item_id == nft_info.item_id {
+            return Err("an NFT cannot be related to itself".into());
+        }
+        // Links may point at records held by other peers, so only warn
+        if find_nft_info(&local_nft_info, &link.collection_name, link.item_id).is_none() {
+            info!(
+                "related NFT {}|{} is not in the local store",
+                link.collection_name, link.item_id
+            );
+        }
+    }
+
+    // Sequence numbers are only ever assigned here, never taken from the input
+    nft_info.seq = None;
+    nft_info.creator = None;
+    nft_info.mirrored_from = None;
+    if !nft_info.draft {
+        let seq = next_seq(&local_nft_info, &nft_info.collection_name, local);
+        set_seq(&mut nft_info, seq, local);
+    }
+
+    let existing = local_nft_info
+        .iter_mut()
+        .find(|r| r.collection_name == nft_info.collection_name && r.item_id == nft_info.item_id);
+    match existing {
+        Some(existing) if existing.burn.is_some() => {
+            return Err(format!(
+                "{}|{} was burned and cannot be recreated",
+                nft_info.collection_name, nft_info.item_id
+            )
+            .into());
+        }
+        Some(existing) if !existing.draft => {
+            return Err(format!(
+                "{}|{} is already published, use UPDATE NFT to change it",
+                nft_info.collection_name, nft_info.item_id
+            )
+            .into());
+        }
+        // A draft is overwritten, and published when the new record isn't a draft
+        Some(existing) => *existing = nft_info.clone(),
+        None => local_nft_info.push(nft_info.clone()),
+    }
+    store.flush(&local_nft_info);
+    drop(local_nft_info);
+    events::emit(Event::Created { record: &nft_info });
+
+    info!(
+        "Created NFT info{}:",
+        if nft_info.draft { " (draft)" } else { "" }
+    );
+    info!("Name: {}", nft_info.collection_name);
+    info!("Item ID: {}", nft_info.item_id);
+    info!("NFT Item Description  {}", nft_info.description);
+    info!("NFT Item owner {}", nft_info.owner);
+    info!("NFT Item attachments {}", nft_info.attachments.len());
+    info!("NFT Item related {}", nft_info.related.len());
+
+    Ok(nft_info)
+}
+
+fn find_nft_info<'a>(
+    records: &'a [NFTInfo],
+    collection_name: &str,
+    item_id: u32,
+) -> Option<&'a NFTInfo> {
+    records
+        .iter()
+        .find(|r| r.collection_name == collection_name && r.item_id == item_id)
+}
+
+fn publish_message<T: Serialize>(
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+    message: &T,
+) -> std::result::Result<MessageId, PublishError> {
+    let json = serde_json::to_string(message).expect("can jsonify message");
+    let topic = swarm.behaviour().namespace.topic();
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .publish(topic, json.as_bytes())
+}
+
+#[derive(Debug, Clone, Default)]
+struct QueryOptions {
+    // Overrides the default timeout of the command class
+    timeout: Option<Duration>,
+    // Only ask the members of this peer group
+    group: Option<String>,
+    // Item to look up in the DHT if no peer returns it
+    dht_item: Option<u32>,
+    // Only ask this peer
+    peer: Option<PeerId>,
+    // Mirror the results into the local store for a FOLLOW
+    mirror: Option<Follow>,
+}
+
+fn send_list_request(
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+    mode: ListMode,
+    options: QueryOptions,
+) -> CommandResult {
+    // Next-best host to ask if the only one asked is slow to answer
+    let mut hedge_with = None;
+    let peers = match &options.group {
+        _ if options.peer.is_some() => options.peer.into_iter().collect(),
+        Some(group) => match swarm.behaviour().groups.get(group) {
+            Some(members) => members.iter().cloned().collect(),
+            None => {
+                return Err(PeerCacheError::InvalidCommand(format!(
+                    "unknown peer group @{}",
+                    group
+                )))
+            }
+        },
+        // Collection queries go to the peers hosting it (all of them or the ones
+        // picked by the selection policy); peers running older versions only
+        // join the global topic, so fall back to that
+        None => match &mode {
+            ListMode::Collection(collection_name)
+            | ListMode::CollectionSince(collection_name, _) => {
+                let namespace = &swarm.behaviour().namespace;
+                let hosts =
+                    match topic_peers(swarm, &namespace.collection_topic(collection_name).hash()) {
+                        peers if peers.is_empty() => topic_peers(swarm, &namespace.topic().hash()),
+                        peers => peers,
+                    };
+                let behaviour = swarm.behaviour_mut();
+                let (peers, backup) = behaviour.selection.select(hosts, &behaviour.latency);
+                hedge_with = backup;
+                peers
+            }
+            _ => topic_peers(swarm, &swarm.behaviour().namespace.topic().hash()),
+        },
+    };
+    let dht_item = options.dht_item;
+    if peers.is_empty() && options.mirror.is_some() {
+        // Followed peers that are offline are simply tried again on the next tick
+        return Ok(());
+    }
+    if peers.is_empty() {
+        // With nobody to ask, go straight to the DHT
+        if let (ListMode::Collection(collection_name), Some(item_id)) = (&mode, dht_item) {
+            if swarm.behaviour().dht_records {
+                info!(
+                    "No peers to ask, looking up {}|{} in the DHT",
+                    collection_name, item_id
+                );
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_record(&dht_key(collection_name, item_id), Quorum::One);
+                return Ok(());
+            }
+        }
+        return Err(PeerCacheError::Other(
+            "no peers to send the request to".to_owned(),
+        ));
+    }
+    let behaviour = swarm.behaviour_mut();
+    if behaviour.queries.pending_count() >= behaviour.resources.max_pending_queries {
+        return Err(PeerCacheError::Other(format!(
+            "{} queries are already waiting for responses, try again later",
+            behaviour.queries.pending_count()
+        )));
+    }
+    let id = behaviour.queries.next_id();
+    let requests: Vec<_> = peers
+        .iter()
+        .map(|peer| {
+            let request_id = behaviour.request_response.send_request(
+                peer,
+                ListRequest {
+                    mode: mode.clone(),
+                    request_id: id,
+                },
+            );
+            behaviour.selection.sent(request_id, *peer);
+            request_id
+        })
+        .collect();
+    if let (Some(backup), [first]) = (hedge_with, requests.as_slice()) {
+        behaviour.selection.hedge(id, mode.clone(), *first, backup);
+    }
+    let background = options.mirror.is_some();
+    behaviour.queries.start(
+        id,
+        mode,
+        options.timeout,
+        requests,
+        dht_item,
+        options.mirror,
+    );
+    if background {
+        debug!("Sync query #{} sent to {} peers", id, peers.len());
+    } else {
+        info!("Query #{} sent to {} peers", id, peers.len());
+    }
+    Ok(())
+}
+
+// Peers subscribed to a topic are the ones serving NFT queries for it. Gossipsub
+// may still list peers that disconnected or were banned since; asking them
+// would only fail.
+fn topic_peers(swarm: &Swarm<NFTInfoBehaviour>, topic: &TopicHash) -> Vec<PeerId> {
+    let behaviour = swarm.behaviour();
+    behaviour
+        .gossipsub
+        .all_peers()
+        .filter(|(peer, topics)| {
+            topics.contains(&topic) && swarm.is_connected(peer) && behaviour.access.is_allowed(peer)
+        })
+        .map(|(peer, _)| *peer)
+        .collect()
+}
+
+// Called after a local record changed: host its collection and mirror it into the DHT
+fn share_record(swarm: &mut Swarm<NFTInfoBehaviour>, nft_info: &NFTInfo) {
+    if nft_info.draft
+        || swarm
+            .behaviour()
+            .local_only
+            .contains(&nft_info.collection_name)
+    {
+        return;
+    }
+    subscribe_collection(swarm, &nft_info.collection_name);
+    put_dht_record(swarm, nft_info);
+}
+
+fn subscribe_collection(swarm: &mut Swarm<NFTInfoBehaviour>, collection_name: &str) {
+    let topic = swarm
+        .behaviour()
+        .namespace
+        .collection_topic(collection_name);
+    if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+        report_command_error(format!(
+            "error subscribing to collection {}: {:?}",
+            collection_name, e
+        ));
+    }
+}
+
+// Mirrors a shareable record into the DHT so it stays retrievable while we are offline
+fn put_dht_record(swarm: &mut Swarm<NFTInfoBehaviour>, nft_info: &NFTInfo) {
+    let behaviour = swarm.behaviour();
+    if !behaviour.dht_records
+        || nft_info.draft
+        || behaviour.local_only.contains(&nft_info.collection_name)
+    {
+        return;
+    }
+    let record = Record::new(
+        dht_key(&nft_info.collection_name, nft_info.item_id),
+        serde_json::to_vec(nft_info).expect("can jsonify NFT info"),
+    );
+    if let Err(e) = swarm
+        .behaviour_mut()
+        .kademlia
+        .put_record(record, Quorum::One)
+    {
+        report_command_error(format!(
+            "error storing {}|{} in the DHT: {:?}",
+            nft_info.collection_name, nft_info.item_id, e
+        ));
+    }
+}
+
+// Splits the trailing "@<group>" and "--timeout <duration>" options off a query command
+fn split_query_options(cmd: &str) -> Result<(&str, QueryOptions)> {
+    let mut options = QueryOptions::default();
+    let cmd = match cmd.rsplit_once(" --timeout ") {
+        Some((cmd, timeout)) => {
+            let timeout = config::parse_duration(timeout)?;
+            if timeout > MAX_TIMEOUT {
+                return Err(format!("--timeout can be at most {:?}", MAX_TIMEOUT).into());
+            }
+            options.timeout = Some(timeout);
+            cmd
+        }
+        None => cmd,
+    };
+    let cmd = match cmd.rsplit_once(" @") {
+        Some((cmd, group)) => {
+            options.group = Some(group.trim().to_owned());
+            cmd
+        }
+        None => cmd,
+    };
+    Ok((cmd, options))
+}
+
+const CREATE_FORMAT: &str = "Format: CREATE NFT collection_name|item_id|description|owner";
+
+type CommandResult = std::result::Result<(), PeerCacheError>;
+
+// Runs one REPL command against the node
+pub async fn execute(
+    line: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    match line {
+        "LIST PEERS" => handle_list_peers(swarm).await,
+        "STATUS" => handle_status(store, swarm),
+        cmd if cmd.starts_with("BOOTSTRAP ") => handle_bootstrap(cmd, swarm)?,
+        cmd if cmd.starts_with("GET NFT") => handle_list_nft_info(cmd, store, swarm).await?,
+        cmd if cmd.starts_with("CREATE NFT") || cmd.starts_with("CREATE DRAFT") => {
+            handle_create_nft_info(cmd, store, swarm).await?
+        }
+        cmd if cmd.starts_with("PUBLISH ") => handle_publish(cmd, store, swarm).await?,
+        cmd if cmd.starts_with("UPDATE NFT ") => handle_update(cmd, store, swarm)?,
+        cmd if cmd.starts_with("DELETE NFT ") => handle_delete(cmd, store, swarm)?,
+        cmd if cmd.starts_with("TRASH ") => handle_trash(cmd, store, swarm)?,
+        cmd if cmd.starts_with("BURN ") => handle_burn(cmd, store, swarm).await?,
+        cmd if cmd.starts_with("SQL ") => handle_sql(cmd, store).await?,
+        cmd if cmd.starts_with("PRIVATE COLLECTION ") || cmd.starts_with("PUBLIC COLLECTION ") => {
+            handle_collection_visibility(cmd, swarm).await
+        }
+        "LIST PRIVATE" => handle_list_private(swarm).await,
+        cmd if cmd.starts_with("GROUP ") => handle_peer_group(cmd, swarm).await?,
+        "LIST GROUPS" => handle_list_groups(swarm).await,
+        cmd if cmd.starts_with("LOG SET ") => handle_log_set(cmd)?,
+        cmd if cmd.starts_with("FOLLOW ") => handle_follow(cmd, store, swarm)?,
+        cmd if cmd.starts_with("UNFOLLOW ") => handle_unfollow(cmd, swarm)?,
+        "LIST FOLLOWING" => handle_list_following(swarm),
+        cmd if cmd.starts_with("BAN ") => handle_ban(cmd.trim_start_matches("BAN "), swarm)?,
+        cmd if cmd.starts_with("UNBAN ") => handle_unban(cmd.trim_start_matches("UNBAN "), swarm)?,
+        "LIST BANNED" => handle_list_banned(swarm),
+        "PEER STATS" => handle_peer_stats(swarm),
+        cmd if cmd.starts_with("SHOW PEER ") => {
+            handle_show_peer(cmd.trim_start_matches("SHOW PEER "), swarm)?
+        }
+        cmd if cmd.starts_with("SEQUENCE COLLECTION ") => handle_sequence_collection(
+            cmd.trim_start_matches("SEQUENCE COLLECTION "),
+            store,
+            swarm,
+        )?,
+        cmd if cmd.starts_with("SUBSCRIBE COLLECTION ") => {
+            handle_subscribe(cmd.trim_start_matches("SUBSCRIBE COLLECTION "), swarm)?
+        }
+        cmd if cmd.starts_with("UNSUBSCRIBE COLLECTION ") => {
+            handle_unsubscribe(cmd.trim_start_matches("UNSUBSCRIBE COLLECTION "), swarm)?
+        }
+        "LIST SUBSCRIPTIONS" => handle_list_subscriptions(swarm),
+        cmd if cmd.starts_with("SLOWLOG") => handle_slowlog(cmd)?,
+        cmd if cmd.starts_with("GENESIS EXPORT") => {
+            handle_genesis_export(cmd, store, swarm).await?
+        }
+        cmd if cmd.starts_with("TOPOLOGY EXPORT") => handle_topology_export(cmd, swarm).await?,
+        _ => return Err(PeerCacheError::UnknownCommand(line.to_owned())),
+    }
+    Ok(())
+}
+
+fn handle_bootstrap(cmd: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let addr = match cmd
+        .strip_prefix("BOOTSTRAP ")
+        .map(|addr| addr.trim().parse::<Multiaddr>())
+    {
+        Some(Ok(addr)) => addr,
+        _ => {
+            return Err(PeerCacheError::InvalidCommand(
+                "Format: BOOTSTRAP <multiaddr>/p2p/<peer id>".to_owned(),
+            ))
+        }
+    };
+    let peer_id = add_bootstrap_peer(swarm, addr)?;
+    info!("Bootstrapping DHT via {}", peer_id);
+    Ok(())
+}
+
+// Adds <multiaddr>/p2p/<peer id> to the DHT routing table and bootstraps from it
+pub fn add_bootstrap_peer(swarm: &mut Swarm<NFTInfoBehaviour>, addr: Multiaddr) -> Result<PeerId> {
+    let peer_id = match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{} does not end with /p2p/<peer id>", addr))?;
+
+    let kademlia = &mut swarm.behaviour_mut().kademlia;
+    kademlia.add_address(&peer_id, addr);
+    kademlia
+        .bootstrap()
+        .map_err(|e| format!("error bootstrapping DHT: {:?}", e))?;
+    Ok(peer_id)
+}
+
+// Every peercache peer runs gossipsub, which tracks the peers we are connected
+// to, whether they were found through mDNS, the DHT or dialed us
+fn connected_peers(swarm: &Swarm<NFTInfoBehaviour>) -> BTreeSet<PeerId> {
+    swarm
+        .behaviour()
+        .gossipsub
+        .all_peers()
+        .map(|(peer, _)| *peer)
+        .filter(|peer| swarm.is_connected(peer))
+        .collect()
+}
+
+async fn handle_list_peers(swarm: &mut Swarm<NFTInfoBehaviour>) {
+    info!("Connected Peers:");
+    let peers = connected_peers(swarm);
+    peers.iter().for_each(|p| info!("{}", p));
+    events::emit(Event::Peers {
+        peers: peers.iter().map(|p| p.to_string()).collect(),
+    });
+}
+
+fn handle_peer_stats(swarm: &Swarm<NFTInfoBehaviour>) {
+    let ms = |rtt: Option<Duration>| rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+    let selection = &swarm.behaviour().selection;
+    let mut peers = swarm
+        .behaviour()
+        .latency
+        .iter()
+        .filter(|(peer, _)| swarm.is_connected(peer))
+        .collect::<Vec<_>>();
+    // Fastest first; peers that never answered a ping go last
+    peers.sort_by_key(|(_, latency)| (latency.average().is_none(), latency.average()));
+    let stats = peers
+        .into_iter()
+        .map(|(peer, latency)| {
+            let quality = selection.quality.get(peer);
+            events::PeerStats {
+                peer: peer.to_string(),
+                last_rtt_ms: ms(latency.last()),
+                avg_rtt_ms: ms(latency.average()),
+                min_rtt_ms: ms(latency.min()),
+                max_rtt_ms: ms(latency.max()),
+                samples: latency.samples(),
+                failures: latency.failures,
+                avg_response_ms: ms(quality.and_then(|q| q.average())),
+                responses: quality.map_or(0, |q| q.responses),
+                query_failures: quality.map_or(0, |q| q.failures),
+                breaker: selection.breaker_state(peer).to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let show = |rtt: Option<f64>| rtt.map_or("-".to_owned(), |rtt| format!("{:.1}", rtt));
+    info!("peer | last ms | avg ms | min ms | max ms | samples | failures | response ms | responses | failed queries | breaker");
+    for s in &stats {
+        info!(
+            "{} | {} | {} | {} | {} | {} | {} | {} | {} | {} | {}",
+            s.peer,
+            show(s.last_rtt_ms),
+            show(s.avg_rtt_ms),
+            show(s.min_rtt_ms),
+            show(s.max_rtt_ms),
+            s.samples,
+            s.failures,
+            show(s.avg_response_ms),
+            s.responses,
+            s.query_failures,
+            s.breaker
+        );
+    }
+    info!("({} peers)", stats.len());
+    events::emit(Event::PeerStats { peers: stats });
+}
+
+fn handle_show_peer(peer: &str, swarm: &Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let peer = parse_peer_id(peer)?;
+    let info = match swarm.behaviour().peer_info.get(&peer) {
+        Some(info) => info,
+        None => {
+            return Err(PeerCacheError::Other(format!(
+                "{} hasn't been identified, is it connected?",
+                peer
+            )))
+        }
+    };
+    info!("Peer: {}", peer);
+    info!("Connected: {}", swarm.is_connected(&peer));
+    info!("Agent version: {}", info.agent_version);
+    info!("Protocol version: {}", info.protocol_version);
+    info!("Protocols:");
+    info.protocols.iter().for_each(|p| info!("  {}", p));
+    info!("Listen addresses:");
+    info.listen_addrs.iter().for_each(|a| info!("  {}", a));
+    // How the peer sees us, i.e. our address from outside
+    info!("Observed address: {}", info.observed_addr);
+    events::emit(Event::PeerInfo {
+        peer: peer.to_string(),
+        agent_version: &info.agent_version,
+        protocol_version: &info.protocol_version,
+        protocols: &info.protocols,
+        listen_addrs: info.listen_addrs.iter().map(|a| a.to_string()).collect(),
+        observed_addr: info.observed_addr.to_string(),
+    });
+    Ok(())
+}
+
+fn handle_status(store: &NftStore, swarm: &Swarm<NFTInfoBehaviour>) {
+    let network = swarm.network_info();
+    let counters = network.connection_counters();
+    let behaviour = swarm.behaviour();
+    let status = Status {
+        peer_id: swarm.local_peer_id().to_string(),
+        listen_addrs: swarm.listeners().map(|a| a.to_string()).collect(),
+        peers: network.num_peers(),
+        established_incoming: counters.num_established_incoming(),
+        established_outgoing: counters.num_established_outgoing(),
+        pending_incoming: counters.num_pending_incoming(),
+        pending_outgoing: counters.num_pending_outgoing(),
+        inbound_requests: behaviour.inbound_requests.len(),
+        max_inbound_requests: behaviour.resources.max_inbound_requests,
+        pending_queries: behaviour.queries.pending_count(),
+        max_pending_queries: behaviour.resources.max_pending_queries,
+        records: store.read().len(),
+        hedged_requests: behaviour.selection.hedged,
+        hedge_wins: behaviour.selection.hedge_wins,
+        version_advisory: behaviour.version_skew().advisory(),
+    };
+    info!("Peer Id: {}", status.peer_id);
+    status
+        .listen_addrs
+        .iter()
+        .for_each(|a| info!("Listening on {}", a));
+    info!(
+        "Connections: {} peers, {} in / {} out established, {} in / {} out pending",
+        status.peers,
+        status.established_incoming,
+        status.established_outgoing,
+        status.pending_incoming,
+        status.pending_outgoing
+    );
+    info!(
+        "Requests being answered: {}/{}",
+        status.inbound_requests, status.max_inbound_requests
+    );
+    info!(
+        "Queries waiting for responses: {}/{}",
+        status.pending_queries, status.max_pending_queries
+    );
+    info!("Records in the local store: {}", status.records);
+    if status.hedged_requests > 0 {
+        info!(
+            "Hedged requests: {}, answered first: {}",
+            status.hedged_requests, status.hedge_wins
+        );
+    }
+    if let Some(advisory) = &status.version_advisory {
+        info!("Version advisory: {}", advisory);
+    }
+    events::emit(Event::Status(&status));
+}
+
+async fn handle_list_nft_info(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let (cmd, mut options) = split_query_options(cmd)
+        .map_err(|e| PeerCacheError::InvalidCommand(format!("invalid --timeout: {}", e)))?;
+    let rest = cmd.strip_prefix("GET NFT ");
+    match rest {
+        Some("ALL") => send_list_request(swarm, ListMode::ALL, options)?,
+        Some(rest) if rest.contains(" SINCE ") => {
+            let (collection_name, seq) = rest.split_once(" SINCE ").expect("SINCE is present");
+            let seq = seq.trim().parse::<u64>().map_err(|_| {
+                PeerCacheError::InvalidCommand(
+                    "Format: GET NFT collection_name SINCE seq".to_owned(),
+                )
+            })?;
+            send_list_request(
+                swarm,
+                ListMode::CollectionSince(collection_name.trim().to_owned(), seq),
+                options,
+            )?
+        }
+        Some(rest) if rest.starts_with("OWNER ") => send_list_request(
+            swarm,
+            ListMode::Owner(rest.trim_start_matches("OWNER ").trim().to_owned()),
+            options,
+        )?,
+        Some(rest) if rest.starts_with("LINKS ") => {
+            handle_nft_links(rest.trim_start_matches("LINKS "), store, options, swarm)?
+        }
+        Some(rest) => {
+            let (collection_name, item_id) = match rest.split_once("|") {
+                Some((collection_name, item_id)) => match item_id.parse::<u32>() {
+                    Ok(item_id) => (collection_name, Some(item_id)),
+                    Err(_) => {
+                        return Err(PeerCacheError::InvalidCommand(
+                            "Format: GET NFT collection_name[|item_id]".to_owned(),
+                        ))
+                    }
+                },
+                None => (rest, None),
+            };
+            options.dht_item = item_id;
+            send_list_request(
+                swarm,
+                ListMode::Collection(collection_name.to_owned()),
+                options,
+            )?
+        }
+        None => {
+            let v = store.read();
+            info!("Local NFTInfo({})", v.len());
+            events::emit(Event::LocalList { data: &v });
+            v.iter().for_each(|r| {
+                if r.draft {
+                    info!("[draft] {:?}", r)
+                } else if r.burn.is_some() {
+                    info!("[burned] {:?}", r)
+                } else {
+                    info!("{:?}", r)
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+async fn handle_create_nft_info(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let (rest, draft) = match cmd.strip_prefix("CREATE NFT ") {
+        Some(rest) => (rest, false),
+        None => match cmd.strip_prefix("CREATE DRAFT ") {
+            Some(rest) => (rest, true),
+            None => return Err(PeerCacheError::InvalidCommand(CREATE_FORMAT.to_owned())),
+        },
+    };
+    let local = *swarm.local_peer_id();
+    if let Some(path) = rest.strip_prefix("--template ") {
+        let nft_info =
+            create_nft_info_from_file(store, &local, Path::new(path.trim()), draft).await?;
+        share_record(swarm, &nft_info);
+        return Ok(());
+    }
+    // The REPL runs the editor itself and sends the saved file with --template
+    if rest.trim() == "--edit" {
+        return Err(PeerCacheError::InvalidCommand(
+            "--edit only works in the interactive REPL, use --template <file>".to_owned(),
+        ));
+    }
+    let (collection_name, item_id, description, owner) = match rest.split('|').collect::<Vec<_>>()[..]
+    {
+        [collection_name, item_id, description, owner, ..] => {
+            (collection_name, item_id, description, owner)
+        }
+        _ => return Err(PeerCacheError::InvalidCommand(CREATE_FORMAT.to_owned())),
+    };
+    let item_id = item_id.trim().parse::<u32>().map_err(|_| {
+        PeerCacheError::InvalidCommand(format!("item id '{}' is not a number", item_id))
+    })?;
+    let nft_info = NFTInfo {
+        collection_name: collection_name.to_string(),
+        item_id,
+        description: description.to_string(),
+        owner: owner.to_string(),
+        draft,
+        ..Default::default()
+    };
+    let nft_info = create_new_nft_info(store, &local, nft_info).await?;
+    share_record(swarm, &nft_info);
+    Ok(())
+}
+
+async fn create_nft_info_from_file(
+    store: &NftStore,
+    local: &PeerId,
+    path: &Path,
+    draft: bool,
+) -> std::result::Result<NFTInfo, PeerCacheError> {
+    let mut nft_info: NFTInfo = serde_json::from_slice(&std::fs::read(path)?)?;
+    if nft_info.collection_name.trim().is_empty() || nft_info.owner.trim().is_empty() {
+        return Err(PeerCacheError::Other(
+            "collection_name and owner must not be empty".to_owned(),
+        ));
+    }
+    nft_info.draft |= draft;
+    Ok(create_new_nft_info(store, local, nft_info).await?)
+}
+
+async fn handle_sql(cmd: &str, store: &NftStore) -> CommandResult {
+    let query = cmd.trim_start_matches("SQL ");
+    let started = Instant::now();
+    let records = store.read();
+    let result = sql::execute(query, &records);
+    slowlog::record(SlowQuery {
+        source: "local".to_owned(),
+        filter: cmd.to_owned(),
+        rows_scanned: records.len(),
+        peers: 0,
+        duration: started.elapsed(),
+    });
+    drop(records);
+    let result =
+        result.map_err(|e| PeerCacheError::InvalidCommand(format!("invalid query: {}", e)))?;
+    info!("{}", result.columns.join(" | "));
+    result.rows.iter().for_each(|r| info!("{}", r.join(" | ")));
+    info!("({} rows)", result.rows.len());
+    events::emit(Event::QueryResult {
+        columns: &result.columns,
+        rows: &result.rows,
+    });
+    Ok(())
+}
+
+fn local_topology(swarm: &Swarm<NFTInfoBehaviour>) -> TopologyAnnouncement {
+    let neighbours = connected_peers(swarm)
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<BTreeSet<_>>();
+    let behaviour = swarm.behaviour();
+    let local = swarm.local_peer_id().to_string();
+    let shareable = shareable_nft_info(&behaviour.store, &behaviour.local_only);
+    let collections = shareable
+        .iter()
+        .map(|r| r.collection_name.clone())
+        .collect::<BTreeSet<_>>();
+    let latency_ms = behaviour
+        .latency
+        .iter()
+        .filter(|(peer, _)| swarm.is_connected(peer))
+        .filter_map(|(peer, latency)| {
+            let average = latency.average()?;
+            Some((peer.to_string(), average.as_secs_f64() * 1000.0))
+        })
+        .collect();
+    let mut roles = Vec::new();
+    if behaviour.dht_records {
+        roles.push("dht-records".to_owned());
+    }
+    if shareable.iter().any(|r| r.creator.as_ref() == Some(&local)) {
+        roles.push("sequencer".to_owned());
+    }
+    if !behaviour.following.is_empty() {
+        roles.push("follower".to_owned());
+    }
+    TopologyAnnouncement {
+        peer: local,
+        neighbours: neighbours.into_iter().collect(),
+        collections: collections.into_iter().collect(),
+        latency_ms,
+        roles,
+    }
+}
+
+pub fn handle_topology_tick(swarm: &mut Swarm<NFTInfoBehaviour>) {
+    let announcement = local_topology(swarm);
+    match publish_message(swarm, &announcement) {
+        // Nobody to gossip to yet, the next tick will try again
+        Ok(_) | Err(PublishError::InsufficientPeers) => (),
+        Err(e) => error!("error publishing topology: {:?}", e),
+    }
+    swarm
+        .behaviour_mut()
+        .topology
+        .retain(|_, (seen, _)| seen.elapsed() < TOPOLOGY_TTL);
+}
+
+async fn handle_topology_export(cmd: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let mut args = cmd
+        .strip_prefix("TOPOLOGY EXPORT")
+        .unwrap_or_default()
+        .split_whitespace();
+    let mut views = vec![local_topology(swarm)];
+    views.extend(swarm.behaviour().topology.values().map(|(_, t)| t.clone()));
+    let local = swarm.local_peer_id().to_string();
+    let rendered = match args.next() {
+        Some("dot") => topology::to_dot(&local, &views),
+        Some("json") => topology::to_json(&local, &views),
+        _ => {
+            return Err(PeerCacheError::InvalidCommand(
+                "Format: TOPOLOGY EXPORT dot|json [file]".to_owned(),
+            ))
+        }
+    };
+    match args.next() {
+        Some(path) => {
+            std::fs::write(path, rendered)?;
+            info!("Wrote topology to {}", path);
+        }
+        None => println!("{}", rendered),
+    }
+    Ok(())
+}
+
+async fn handle_genesis_export(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let path = match cmd.strip_prefix("GENESIS EXPORT ") {
+        Some(path) => Path::new(path.trim()),
+        None => {
+            return Err(PeerCacheError::InvalidCommand(
+                "Format: GENESIS EXPORT <file>".to_owned(),
+            ))
+        }
+    };
+    // Every node imports the file, so drafts and local-only collections stay out
+    let behaviour = swarm.behaviour();
+    let records = shareable_nft_info(store, &behaviour.local_only)
+        .into_iter()
+        .map(|r| genesis::GenesisEntry::sign(r, &behaviour.keys))
+        .collect::<Result<Vec<_>>>()?;
+    genesis::write(path, &genesis::GenesisFile { records })?;
+    info!("Wrote signed genesis file to {}", path.display());
+    Ok(())
+}
+
+async fn handle_collection_visibility(cmd: &str, swarm: &mut Swarm<NFTInfoBehaviour>) {
+    if let Some(collection_name) = cmd.strip_prefix("PRIVATE COLLECTION ") {
+        let behaviour = swarm.behaviour_mut();
+        behaviour.local_only.insert(collection_name.trim());
+        // Stop advertising that we host it
+        let topic = behaviour.namespace.collection_topic(collection_name.trim());
+        let _ = behaviour.gossipsub.unsubscribe(&topic);
+        info!("Collection {} is now local-only", collection_name.trim());
+    } else if let Some(collection_name) = cmd.strip_prefix("PUBLIC COLLECTION ") {
+        // Advertise it again, PRIVATE COLLECTION left its topic
+        if swarm.behaviour().local_only.remove(collection_name.trim()) {
+            subscribe_collection(swarm, collection_name.trim());
+        }
+        info!(
+            "Collection {} is now shared with peers",
+            collection_name.trim()
+        );
+    }
+}
+
+async fn handle_list_private(swarm: &Swarm<NFTInfoBehaviour>) {
+    let local_only = swarm.behaviour().local_only.list();
+    info!("Local-only collections({})", local_only.len());
+    local_only.iter().for_each(|c| info!("{}", c));
+}
+
+const GROUP_SET_FORMAT: &str = "Format: GROUP SET <name> <peer id> [<peer id> ...]";
+
+async fn handle_peer_group(cmd: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let groups = &mut swarm.behaviour_mut().groups;
+    if let Some(rest) = cmd.strip_prefix("GROUP SET ") {
+        let mut elements = rest.split_whitespace();
+        let name = match elements.next() {
+            Some(name) => name.trim_start_matches('@').to_owned(),
+            None => return Err(PeerCacheError::InvalidCommand(GROUP_SET_FORMAT.to_owned())),
+        };
+        let members = elements
+            .map(parse_peer_id)
+            .collect::<std::result::Result<BTreeSet<_>, _>>()?;
+        if members.is_empty() {
+            return Err(PeerCacheError::InvalidCommand(GROUP_SET_FORMAT.to_owned()));
+        }
+        info!("Peer group @{} has {} members", name, members.len());
+        groups.insert(name, members);
+    } else if let Some(name) = cmd.strip_prefix("GROUP DELETE ") {
+        let name = name.trim().trim_start_matches('@');
+        if groups.remove(name).is_none() {
+            return Err(PeerCacheError::Other(format!(
+                "unknown peer group @{}",
+                name
+            )));
+        }
+        info!("Deleted peer group @{}", name);
+    } else {
+        return Err(PeerCacheError::InvalidCommand(format!(
+            "{} / GROUP DELETE <name>",
+            GROUP_SET_FORMAT
+        )));
+    }
+    Ok(())
+}
+
+fn parse_peer_id(peer: &str) -> std::result::Result<PeerId, PeerCacheError> {
+    peer.trim()
+        .parse::<PeerId>()
+        .map_err(|_| PeerCacheError::InvalidCommand(format!("'{}' is not a peer id", peer.trim())))
+}
+
+fn handle_ban(peer: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let peer = parse_peer_id(peer)?;
+    if peer == *swarm.local_peer_id() {
+        return Err(PeerCacheError::InvalidCommand(
+            "a node cannot ban itself".to_owned(),
+        ));
+    }
+    if !swarm.behaviour_mut().access.ban(peer) {
+        return Err(PeerCacheError::Other(format!("{} is already banned", peer)));
+    }
+    // Closes the open connections and drops gossip from or through the peer
+    swarm.ban_peer_id(peer);
+    swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+    swarm.behaviour_mut().forget_peer(&peer);
+    info!("Banned {}", peer);
+    Ok(())
+}
+
+fn handle_unban(peer: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let peer = parse_peer_id(peer)?;
+    if !swarm.behaviour_mut().access.unban(&peer) {
+        return Err(PeerCacheError::Other(format!("{} is not banned", peer)));
+    }
+    // The config's allowlist and blocklist still apply
+    if swarm.behaviour().access.is_allowed(&peer) {
+        swarm.unban_peer_id(peer);
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .remove_blacklisted_peer(&peer);
+        info!("Unbanned {}", peer);
+    } else {
+        info!("Unbanned {}, but the config still refuses it", peer);
+    }
+    Ok(())
+}
+
+fn handle_list_banned(swarm: &Swarm<NFTInfoBehaviour>) {
+    let banned = swarm.behaviour().access.banned();
+    info!("Banned peers({})", banned.len());
+    banned.iter().for_each(|p| info!("{}", p));
+}
+
+fn handle_follow(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let follow = Follow::parse(cmd.trim_start_matches("FOLLOW ")).ok_or_else(|| {
+        PeerCacheError::InvalidCommand("Format: FOLLOW <peer id> / FOLLOW OWNER <owner>".to_owned())
+    })?;
+    if let Follow::Creator(peer) = &follow {
+        if peer == swarm.local_peer_id() {
+            return Err(PeerCacheError::InvalidCommand(
+                "a node cannot follow itself".to_owned(),
+            ));
+        }
+    }
+    if !swarm.behaviour_mut().following.insert(follow.clone()) {
+        return Err(PeerCacheError::Other(format!(
+            "already following {}",
+            follow
+        )));
+    }
+    info!("Following {}", follow);
+    sync_follow(store, swarm, follow)
+}
+
+fn handle_unfollow(cmd: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    match Follow::parse(cmd.trim_start_matches("UNFOLLOW ")) {
+        Some(follow) if swarm.behaviour_mut().following.remove(&follow) => {
+            // Mirrored records stay in the store but are no longer updated
+            info!("No longer following {}", follow);
+            Ok(())
+        }
+        Some(follow) => Err(PeerCacheError::Other(format!("not following {}", follow))),
+        None => Err(PeerCacheError::InvalidCommand(
+            "Format: UNFOLLOW <peer id> / UNFOLLOW OWNER <owner>".to_owned(),
+        )),
+    }
+}
+
+fn handle_list_following(swarm: &Swarm<NFTInfoBehaviour>) {
+    let following = swarm.behaviour().following.list();
+    info!("Following({})", following.len());
+    following.iter().for_each(|f| info!("{}", f));
+}
+
+pub fn handle_follow_tick(store: &NftStore, swarm: &mut Swarm<NFTInfoBehaviour>) {
+    let following = swarm.behaviour().following.list();
+    for follow in following {
+        if let Err(e) = sync_follow(store, swarm, follow) {
+            report_command_error(e.to_string());
+        }
+    }
+}
+
+// Asks a followed node for the collections it created, using SINCE for the ones
+// already mirrored, or everyone for the records of a followed owner
+fn sync_follow(
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+    follow: Follow,
+) -> CommandResult {
+    let peer = match &follow {
+        Follow::Creator(peer) => *peer,
+        Follow::Owner(owner) => {
+            let options = QueryOptions {
+                mirror: Some(follow.clone()),
+                ..Default::default()
+            };
+            return send_list_request(swarm, ListMode::Owner(owner.clone()), options);
+        }
+    };
+    let synced = follow::synced_collections(store, &peer);
+    // Collections the peer announced but we don't hold yet are fetched in full
+    let announced = swarm
+        .behaviour()
+        .topology
+        .get(&peer.to_string())
+        .map(|(_, announcement)| announcement.collections.clone())
+        .unwrap_or_default();
+    let mut modes = synced
+        .iter()
+        .map(|(collection_name, seq)| ListMode::CollectionSince(collection_name.clone(), *seq))
+        .collect::<Vec<_>>();
+    modes.extend(
+        announced
+            .into_iter()
+            .filter(|c| !synced.iter().any(|(synced, _)| synced == c))
+            .map(ListMode::Collection),
+    );
+    // Nothing known about the peer yet, ask for everything it has
+    if modes.is_empty() {
+        modes.push(ListMode::ALL);
+    }
+    for mode in modes {
+        let options = QueryOptions {
+            peer: Some(peer),
+            mirror: Some(follow.clone()),
+            ..Default::default()
+        };
+        send_list_request(swarm, mode, options)?;
+    }
+    Ok(())
+}
+
+// Makes this node the authoritative creator of a collection: its records are
+// numbered in item id order and every later change gets the next number
+fn handle_sequence_collection(
+    collection_name: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let collection_name = collection_name.trim();
+    let local = *swarm.local_peer_id();
+    let mut records = store.write();
+    if next_seq(&records, collection_name, &local).is_some() {
+        return Err(PeerCacheError::Other(format!(
+            "{} is already sequenced",
+            collection_name
+        )));
+    }
+    // Records mirrored from other nodes keep their creator, or their burns
+    // would no longer verify
+    let mut sequenced = records
+        .iter_mut()
+        .filter(|r| r.collection_name == collection_name && !r.draft && follow::is_local(r, &local))
+        .collect::<Vec<_>>();
+    if sequenced.is_empty() {
+        return Err(PeerCacheError::Other(format!(
+            "no published NFTs created here in {} to sequence",
+            collection_name
+        )));
+    }
+    sequenced.sort_by_key(|r| r.item_id);
+    for (seq, nft_info) in sequenced.iter_mut().enumerate() {
+        set_seq(nft_info, Some(seq as u64 + 1), &local);
+    }
+    let sequenced = sequenced.into_iter().map(|r| r.clone()).collect::<Vec<_>>();
+    store.flush(&records);
+    drop(records);
+    info!(
+        "Sequenced {} records of {}",
+        sequenced.len(),
+        collection_name
+    );
+    sequenced.iter().for_each(|r| share_record(swarm, r));
+    Ok(())
+}
+
+fn handle_subscribe(collection_name: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let collection_name = collection_name.trim();
+    if swarm.behaviour().local_only.contains(collection_name) {
+        return Err(PeerCacheError::Other(format!(
+            "{} is local-only, make it public first",
+            collection_name
+        )));
+    }
+    let topic = swarm
+        .behaviour()
+        .namespace
+        .collection_topic(collection_name);
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .subscribe(&topic)
+        .map_err(|e| {
+            PeerCacheError::Other(format!(
+                "error subscribing to collection {}: {:?}",
+                collection_name, e
+            ))
+        })?;
+    info!("Subscribed to collection {}", collection_name);
+    Ok(())
+}
+
+fn handle_unsubscribe(collection_name: &str, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    let collection_name = collection_name.trim();
+    let topic = swarm
+        .behaviour()
+        .namespace
+        .collection_topic(collection_name);
+    match swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+        Ok(true) => {
+            info!("Unsubscribed from collection {}", collection_name);
+            Ok(())
+        }
+        Ok(false) => Err(PeerCacheError::Other(format!(
+            "not subscribed to {}",
+            collection_name
+        ))),
+        Err(e) => Err(PeerCacheError::Other(format!(
+            "error unsubscribing from {}: {:?}",
+            collection_name, e
+        ))),
+    }
+}
+
+fn handle_list_subscriptions(swarm: &Swarm<NFTInfoBehaviour>) {
+    let prefix = swarm.behaviour().namespace.collection_topic("").hash();
+    let collections = swarm
+        .behaviour()
+        .gossipsub
+        .topics()
+        .filter_map(|hash| hash.as_str().strip_prefix(prefix.as_str()))
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>();
+    info!("Subscribed collections({})", collections.len());
+    collections.iter().for_each(|c| info!("{}", c));
+}
+
+fn handle_log_set(cmd: &str) -> CommandResult {
+    let (target, level) = cmd
+        .strip_prefix("LOG SET ")
+        .and_then(|rest| rest.trim().split_once('='))
+        .ok_or_else(|| {
+            PeerCacheError::InvalidCommand("Format: LOG SET <target>=<level>".to_owned())
+        })?;
+    let level = level.trim().parse::<LevelFilter>().map_err(|_| {
+        PeerCacheError::InvalidCommand(format!(
+            "invalid log level '{}', use off, error, warn, info, debug or trace",
+            level.trim()
+        ))
+    })?;
+    logging::set_level(target.trim(), level)?;
+    info!("Log level for {} set to {}", target.trim(), level);
+    Ok(())
+}
+
+fn handle_slowlog(cmd: &str) -> CommandResult {
+    match cmd.strip_prefix("SLOWLOG").map(str::trim) {
+        Some("") => {
+            let entries = slowlog::entries();
+            info!(
+                "Slow queries({}, threshold {:?})",
+                entries.len(),
+                slowlog::threshold()
+            );
+            info!("source | filter | rows scanned | peers | duration");
+            entries.iter().for_each(|q| {
+                info!(
+                    "{} | {} | {} | {} | {:?}",
+                    q.source, q.filter, q.rows_scanned, q.peers, q.duration
+                )
+            });
+        }
+        Some("RESET") => {
+            slowlog::reset();
+            info!("Slow query log cleared");
+        }
+        Some(rest) if rest.starts_with("THRESHOLD ") => {
+            let threshold = config::parse_duration(rest.trim_start_matches("THRESHOLD "))
+                .map_err(|e| PeerCacheError::InvalidCommand(format!("invalid threshold: {}", e)))?;
+            slowlog::set_threshold(threshold);
+            info!("Logging queries slower than {:?}", threshold);
+        }
+        _ => {
+            return Err(PeerCacheError::InvalidCommand(
+                "Format: SLOWLOG [RESET | THRESHOLD <duration>]".to_owned(),
+            ))
+        }
+    }
+    Ok(())
+}
+
+async fn handle_list_groups(swarm: &Swarm<NFTInfoBehaviour>) {
+    let groups = &swarm.behaviour().groups;
+    info!("Peer groups({})", groups.len());
+    for (name, members) in groups.iter() {
+        info!("@{}:", name);
+        members.iter().for_each(|p| info!("  {}", p));
+    }
+}
+
+// Parses the collection_name|item_id argument of PUBLISH, DELETE NFT, TRASH
+// RESTORE and BURN
+fn parse_record_ref<'a>(
+    args: &'a str,
+    format: &str,
+) -> std::result::Result<(&'a str, u32), PeerCacheError> {
+    let elements: Vec<&str> = args.split('|').collect();
+    match (
+        elements.first(),
+        elements.get(1).and_then(|id| id.parse::<u32>().ok()),
+    ) {
+        (Some(collection_name), Some(item_id)) => Ok((*collection_name, item_id)),
+        _ => Err(PeerCacheError::InvalidCommand(format.to_owned())),
+    }
+}
+
+async fn handle_publish(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let args = cmd.trim_start_matches("PUBLISH ");
+    // None publishes every draft of the collection
+    let (collection_name, item_id) = match args.strip_prefix("COLLECTION ") {
+        Some(collection_name) if !collection_name.trim().is_empty() => {
+            (collection_name.trim(), None)
+        }
+        Some(_) => {
+            return Err(PeerCacheError::InvalidCommand(
+                "Format: PUBLISH COLLECTION collection_name".to_owned(),
+            ))
+        }
+        None => {
+            let (collection_name, item_id) = parse_record_ref(
+                args,
+                "Format: PUBLISH collection_name|item_id or PUBLISH COLLECTION collection_name",
+            )?;
+            (collection_name, Some(item_id))
+        }
+    };
+    let local = *swarm.local_peer_id();
+    let mut records = store.write();
+    let mut seq = next_seq(&records, collection_name, &local);
+    let mut drafts = records
+        .iter_mut()
+        .filter(|r| {
+            r.draft
+                && r.collection_name == collection_name
+                && item_id.map_or(true, |item_id| r.item_id == item_id)
+        })
+        .collect::<Vec<_>>();
+    // Drafts are sequenced in item order
+    drafts.sort_by_key(|r| r.item_id);
+    let mut published = Vec::new();
+    for nft_info in drafts {
+        nft_info.draft = false;
+        set_seq(nft_info, seq, &local);
+        seq = seq.map(|seq| seq + 1);
+        published.push(nft_info.clone());
+    }
+    if published.is_empty() {
+        return Err(PeerCacheError::Other(match item_id {
+            Some(item_id) => format!("no draft {}|{} to publish", collection_name, item_id),
+            None => format!("no drafts of {} to publish", collection_name),
+        }));
+    }
+    store.flush(&records);
+    drop(records);
+    for nft_info in &published {
+        info!("Published {}|{}", collection_name, nft_info.item_id);
+        events::emit(Event::Published {
+            collection_name,
+            item_id: nft_info.item_id,
+        });
+        share_record(swarm, nft_info);
+    }
+    Ok(())
+}
+
+fn handle_update(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let elements: Vec<&str> = cmd
+        .trim_start_matches("UPDATE NFT ")
+        .splitn(3, '|')
+        .collect();
+    let (collection_name, item_id, (field, value)) = match (
+        elements.first(),
+        elements.get(1).and_then(|id| id.parse::<u32>().ok()),
+        elements
+            .get(2)
+            .and_then(|assignment| assignment.split_once('=')),
+    ) {
+        (Some(collection_name), Some(item_id), Some(assignment)) => {
+            (*collection_name, item_id, assignment)
+        }
+        _ => {
+            return Err(PeerCacheError::InvalidCommand(
+                "Format: UPDATE NFT collection_name|item_id|field=value".to_owned(),
+            ))
+        }
+    };
+    let local = *swarm.local_peer_id();
+    let nft_info = store
+        .update(&local, collection_name, item_id, field.trim(), value)
+        .map_err(|e| PeerCacheError::Other(format!("error updating NFT info: {}", e)))?;
+    info!(
+        "Updated {} of {}|{}",
+        field.trim(),
+        collection_name,
+        item_id
+    );
+    events::emit(Event::Updated { record: &nft_info });
+    share_record(swarm, &nft_info);
+    Ok(())
+}
+
+fn handle_delete(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let (collection_name, item_id) = parse_record_ref(
+        cmd.trim_start_matches("DELETE NFT "),
+        "Format: DELETE NFT collection_name|item_id",
+    )?;
+    let local = *swarm.local_peer_id();
+    let deleted = store
+        .delete(&local, collection_name, item_id)
+        .map_err(|e| PeerCacheError::Other(format!("error deleting NFT info: {}", e)))?;
+    swarm.behaviour_mut().trash.put(deleted);
+    info!("Deleted {}|{}", collection_name, item_id);
+    events::emit(Event::Deleted {
+        collection_name,
+        item_id,
+    });
+    // Only our own copy of the DHT record can be dropped, replicas expire on their own
+    swarm
+        .behaviour_mut()
+        .kademlia
+        .remove_record(&dht_key(collection_name, item_id));
+    Ok(())
+}
+
+fn handle_trash(cmd: &str, store: &NftStore, swarm: &mut Swarm<NFTInfoBehaviour>) -> CommandResult {
+    if cmd == "TRASH LIST" {
+        let entries = swarm.behaviour_mut().trash.list();
+        info!("Trash({})", entries.len());
+        for entry in entries {
+            info!(
+                "{}|{} deleted at {} (Unix time): {:?}",
+                entry.record.collection_name, entry.record.item_id, entry.deleted_at, entry.record
+            );
+        }
+        events::emit(Event::Trash { entries });
+        return Ok(());
+    }
+    let (collection_name, item_id) = parse_record_ref(
+        cmd.strip_prefix("TRASH RESTORE ")
+            .ok_or_else(|| PeerCacheError::InvalidCommand(TRASH_FORMAT.to_owned()))?,
+        TRASH_FORMAT,
+    )?;
+    let mut records = store.write();
+    if find_nft_info(&records, collection_name, item_id).is_some() {
+        return Err(PeerCacheError::Other(format!(
+            "{}|{} was created again since it was deleted",
+            collection_name, item_id
+        )));
+    }
+    let restored = swarm
+        .behaviour_mut()
+        .trash
+        .restore(collection_name, item_id)
+        .map_err(|e| PeerCacheError::Other(e.to_string()))?;
+    records.push(restored.clone());
+    store.flush(&records);
+    drop(records);
+    info!("Restored {}|{}", collection_name, item_id);
+    events::emit(Event::Restored { record: &restored });
+    share_record(swarm, &restored);
+    Ok(())
+}
+
+const TRASH_FORMAT: &str = "Format: TRASH LIST / TRASH RESTORE collection_name|item_id";
+
+fn handle_nft_links(
+    args: &str,
+    store: &NftStore,
+    options: QueryOptions,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let (target, network) = match args.trim().strip_suffix("--network") {
+        Some(target) => (target.trim(), true),
+        None => (args.trim(), false),
+    };
+    let records = store.read();
+    let elements: Vec<&str> = target.split("|").collect();
+    let start = match (
+        elements.first(),
+        elements.get(1).and_then(|id| id.parse::<u32>().ok()),
+    ) {
+        (Some(collection_name), Some(item_id)) => find_nft_info(&records, collection_name, item_id),
+        _ => {
+            return Err(PeerCacheError::InvalidCommand(
+                "Format: GET NFT LINKS collection_name|item_id [--network]".to_owned(),
+            ))
+        }
+    };
+    let start = start
+        .ok_or_else(|| PeerCacheError::Other(format!("no NFT {} in the local store", target)))?;
+
+    info!("Links of {}|{}:", start.collection_name, start.item_id);
+    let mut seen = HashSet::new();
+    seen.insert((start.collection_name.clone(), start.item_id));
+    let mut missing_collections = BTreeSet::new();
+    print_nft_links(&records, start, 1, &mut seen, &mut missing_collections);
+
+    records
+        .iter()
+        .filter(|r| {
+            r.related
+                .iter()
+                .any(|l| l.collection_name == start.collection_name && l.item_id == start.item_id)
+        })
+        .for_each(|r| info!("referenced by {}|{}", r.collection_name, r.item_id));
+    drop(records);
+
+    if network {
+        for collection_name in missing_collections {
+            info!("Requesting collection {} from the network", collection_name);
+            send_list_request(
+                swarm,
+                ListMode::Collection(collection_name),
+                options.clone(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Depth-first walk over outgoing links, showing every record at most once
+fn print_nft_links(
+    records: &[NFTInfo],
+    nft_info: &NFTInfo,
+    depth: usize,
+    seen: &mut HashSet<(String, u32)>,
+    missing_collections: &mut BTreeSet<String>,
+) {
+    let indent = "  ".repeat(depth);
+    for link in &nft_info.related {
+        let key = (link.collection_name.clone(), link.item_id);
+        match find_nft_info(records, &link.collection_name, link.item_id) {
+            Some(target) if seen.insert(key) => {
+                info!(
+                    "{}{} -> {}|{}",
+                    indent, link.relation, link.collection_name, link.item_id
+                );
+                print_nft_links(records, target, depth + 1, seen, missing_collections);
+            }
+            Some(_) => info!(
+                "{}{} -> {}|{} (see above)",
+                indent, link.relation, link.collection_name, link.item_id
+            ),
+            None => {
+                info!(
+                    "{}{} -> {}|{} (not in local store)",
+                    indent, link.relation, link.collection_name, link.item_id
+                );
+                missing_collections.insert(link.collection_name.clone());
+            }
+        }
+    }
+}
+
+async fn handle_burn(
+    cmd: &str,
+    store: &NftStore,
+    swarm: &mut Swarm<NFTInfoBehaviour>,
+) -> CommandResult {
+    let (collection_name, item_id) = parse_record_ref(
+        cmd.trim_start_matches("BURN "),
+        "Format: BURN collection_name|item_id",
+    )?;
+    let local = *swarm.local_peer_id();
+    let mut records = store.write();
+    let seq = next_seq(&records, collection_name, &local);
+    let nft_info = records
+        .iter_mut()
+        .find(|r| r.collection_name == collection_name && r.item_id == item_id)
+        .ok_or_else(|| {
+            PeerCacheError::Other(format!("no NFT {}|{} to burn", collection_name, item_id))
+        })?;
+    if nft_info.draft {
+        return Err(PeerCacheError::Other(format!(
+            "{}|{} is a draft and can simply be overwritten",
+            collection_name, item_id
+        )));
+    }
+    if nft_info.burn.is_some() {
+        return Err(PeerCacheError::Other(format!(
+            "{}|{} is already burned",
+            collection_name, item_id
+        )));
+    }
+    let burn = BurnStatement::sign(&swarm.behaviour().keys, collection_name, item_id)
+        .map_err(|e| PeerCacheError::Other(format!("error signing burn statement: {}", e)))?;
+    nft_info.burn = Some(burn);
+    set_seq(nft_info, seq, &local);
+    let burned = nft_info.clone();
+    store.flush(&records);
+    drop(records);
+    info!("Burned {}|{}", collection_name, item_id);
+    // Replace the DHT copy so the burn is visible there too
+    share_record(swarm, &burned);
+    Ok(())
+}
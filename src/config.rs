@@ -0,0 +1,405 @@
+use crate::{ListMode, Result};
+use libp2p::core::network::ConnectionLimits;
+use libp2p::gossipsub::{GossipsubConfig, GossipsubConfigBuilder, ValidationMode};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Settings read from peercache.toml for long-lived nodes. Everything is optional;
+// command line flags take precedence over the values in the file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<Multiaddr>,
+    // <multiaddr>/p2p/<peer id> of nodes to bootstrap the DHT from on startup
+    pub bootstrap: Vec<Multiaddr>,
+    pub data_dir: Option<PathBuf>,
+    pub identity: Option<PathBuf>,
+    pub keyring: Option<bool>,
+    pub swarm_key: Option<PathBuf>,
+    pub namespace: Option<String>,
+    pub mdns: Option<bool>,
+    pub dht_records: Option<bool>,
+    // Peer id that signed the genesis.json to import
+    pub genesis_key: Option<String>,
+    // Peer ids; when allow isn't empty only those peers may connect
+    pub allow: Vec<String>,
+    pub block: Vec<String>,
+    // [groups] table of peer groups for @<group> queries, e.g. museum = ["12D3KooW..."]
+    pub groups: BTreeMap<String, Vec<String>>,
+    // The [gossip], [timeouts], [connections], [resources], [selection] and
+    // [trash] tables; there are no flags for these
+    pub gossip: GossipConfig,
+    pub timeouts: TimeoutConfig,
+    pub connections: ConnectionConfig,
+    pub resources: ResourceConfig,
+    pub selection: SelectionConfig,
+    pub trash: TrashConfig,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("can't read config file {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e).into())
+    }
+
+    pub fn allow(&self) -> Result<Vec<PeerId>> {
+        parse_peer_ids(&self.allow)
+    }
+
+    pub fn block(&self) -> Result<Vec<PeerId>> {
+        parse_peer_ids(&self.block)
+    }
+
+    pub fn genesis_key(&self) -> Result<Option<PeerId>> {
+        match &self.genesis_key {
+            Some(key) => {
+                Ok(Some(key.parse::<PeerId>().map_err(|_| {
+                    format!("invalid genesis_key '{}' in config file", key)
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn groups(&self) -> Result<BTreeMap<String, BTreeSet<PeerId>>> {
+        self.groups
+            .iter()
+            .map(|(name, members)| -> Result<(String, BTreeSet<PeerId>)> {
+                let members = parse_peer_ids(members)?.into_iter().collect();
+                Ok((name.trim_start_matches('@').to_owned(), members))
+            })
+            .collect()
+    }
+
+    // Loads the file if it exists; a missing default file just means no settings
+    pub fn load_or_default(path: &Path) -> Result<Config> {
+        match fs::metadata(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            _ => Config::load(path),
+        }
+    }
+}
+
+fn parse_peer_ids(peers: &[String]) -> Result<Vec<PeerId>> {
+    peers
+        .iter()
+        .map(|p| {
+            p.parse::<PeerId>()
+                .map_err(|_| format!("invalid peer id '{}' in config file", p).into())
+        })
+        .collect()
+}
+
+// Durations in the config file are written like on the command line, e.g. "10s"
+fn deserialize_duration<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+// Mesh and heartbeat settings for the gossipsub behaviour. Peers keep between
+// mesh_n_low and mesh_n_high full-message links per topic and only gossip
+// message ids to gossip_lazy others, instead of flooding every peer.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipConfig {
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub heartbeat_interval: Duration,
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    pub gossip_lazy: usize,
+    pub history_length: usize,
+    pub history_gossip: usize,
+    pub max_transmit_size: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            heartbeat_interval: Duration::from_secs(1),
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            gossip_lazy: 6,
+            history_length: 5,
+            history_gossip: 3,
+            max_transmit_size: 65536,
+        }
+    }
+}
+
+impl GossipConfig {
+    pub fn build(&self) -> Result<GossipsubConfig> {
+        GossipsubConfigBuilder::default()
+            .heartbeat_interval(self.heartbeat_interval)
+            .mesh_n(self.mesh_n)
+            .mesh_n_low(self.mesh_n_low)
+            .mesh_n_high(self.mesh_n_high)
+            .gossip_lazy(self.gossip_lazy)
+            .history_length(self.history_length)
+            .history_gossip(self.history_gossip)
+            .max_transmit_size(self.max_transmit_size)
+            // Only accept messages signed by their author
+            .validation_mode(ValidationMode::Strict)
+            .build()
+            .map_err(|e| format!("invalid gossipsub config: {}", e).into())
+    }
+}
+
+// Limits that keep a single hostile host from exhausting file descriptors or
+// stalling the swarm with connections that never finish their handshake.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    // Inbound connections still negotiating noise and mplex
+    pub max_pending_incoming: u32,
+    pub max_established_incoming: u32,
+    pub max_established_per_peer: u32,
+    // Inbound connections from one IP address, established or still negotiating
+    pub max_connections_per_ip: usize,
+    // Connections that haven't finished the transport upgrade by then are dropped
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub negotiation_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            max_pending_incoming: 32,
+            max_established_incoming: 128,
+            max_established_per_peer: 2,
+            max_connections_per_ip: 8,
+            negotiation_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    pub fn limits(&self) -> ConnectionLimits {
+        ConnectionLimits::default()
+            .with_max_pending_incoming(Some(self.max_pending_incoming))
+            .with_max_established_incoming(Some(self.max_established_incoming))
+            .with_max_established_per_peer(Some(self.max_established_per_peer))
+    }
+}
+
+// Budgets for work other peers (or the REPL) can queue up on this node. Each
+// answered request buffers at most one response (capped by the codec at 4 MiB),
+// so max_inbound_requests also bounds the memory held for outgoing responses.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceConfig {
+    // Requests from peers that are queued or being answered; more are refused
+    pub max_inbound_requests: usize,
+    // Worker tasks answering requests concurrently
+    pub request_workers: usize,
+    // Requests taken off the queue per second, across all workers
+    pub max_requests_per_second: u32,
+    // Network queries of our own waiting for responses
+    pub max_pending_queries: usize,
+}
+
+impl Default for ResourceConfig {
+    fn default() -> Self {
+        ResourceConfig {
+            max_inbound_requests: 64,
+            request_workers: 4,
+            max_requests_per_second: 200,
+            max_pending_queries: 32,
+        }
+    }
+}
+
+// Which of the peers hosting a collection a collection query is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SelectionPolicy {
+    // Every host
+    All,
+    // The host that answered fastest so far
+    Fastest,
+    // One host at a time, in turn
+    RoundRobin,
+    // k hosts picked at random
+    RandomK,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectionConfig {
+    pub policy: SelectionPolicy,
+    // Hosts asked by the random-k policy
+    pub k: usize,
+    // When a query sent to one host isn't answered within the hedge_percentile
+    // of recent response times, ask the next-best host as well
+    pub hedge: bool,
+    pub hedge_percentile: u8,
+    // Failed queries in a row after which a host is skipped (0 never skips),
+    // until a probe query after breaker_cooldown is answered
+    pub breaker_failures: u32,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for SelectionConfig {
+    fn default() -> Self {
+        SelectionConfig {
+            policy: SelectionPolicy::All,
+            k: 2,
+            hedge: false,
+            hedge_percentile: 95,
+            breaker_failures: 3,
+            breaker_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+// How long DELETE NFT keeps records in the trash for TRASH RESTORE; zero
+// deletes them right away
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub window: Duration,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig {
+            window: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+// How long a network query waits for peer responses, per command class.
+// Individual commands can override this with --timeout.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub list_all: Duration,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub list_collection: Duration,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub list_owner: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            list_all: Duration::from_secs(10),
+            list_collection: Duration::from_secs(5),
+            list_owner: Duration::from_secs(5),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    pub fn for_mode(&self, mode: &ListMode) -> Duration {
+        match mode {
+            ListMode::ALL => self.list_all,
+            ListMode::Collection(_) | ListMode::CollectionSince(..) => self.list_collection,
+            ListMode::Owner(_) => self.list_owner,
+        }
+    }
+}
+
+// Parses durations like "500ms", "10s" or "2m"; a bare number is taken as seconds
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => number
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("duration '{}' is too long", value).into()),
+        _ => Err(format!("invalid duration unit in '{}', use ms, s or m", value).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration(" 2m ").unwrap(), Duration::from_secs(120));
+        // A bare number is taken as seconds
+        assert_eq!(parse_duration("7").unwrap(), Duration::from_secs(7));
+        assert_eq!(parse_duration("0s").unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("1.5s").is_err());
+        assert!(parse_duration("10h").is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        let too_long = format!("{}m", u64::MAX / 60 + 1);
+        assert!(parse_duration(&too_long)
+            .unwrap_err()
+            .to_string()
+            .contains("too long"));
+        let longest = format!("{}m", u64::MAX / 60);
+        assert!(parse_duration(&longest).is_ok());
+    }
+
+    #[test]
+    fn reads_durations_in_the_config_file() {
+        let config: Config = toml::from_str(
+            r#"
+            [timeouts]
+            list_all = "30s"
+
+            [connections]
+            negotiation_timeout = "1m"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.timeouts.list_all, Duration::from_secs(30));
+        assert_eq!(
+            config.timeouts.list_owner,
+            TimeoutConfig::default().list_owner
+        );
+        assert_eq!(
+            config.connections.negotiation_timeout,
+            Duration::from_secs(60)
+        );
+        assert!(toml::from_str::<Config>("[timeouts]\nlist_all = \"soon\"").is_err());
+    }
+
+    #[test]
+    fn reads_the_selection_policy() {
+        let config: Config = toml::from_str("[selection]\npolicy = \"random-k\"\nk = 3").unwrap();
+        assert_eq!(config.selection.policy, SelectionPolicy::RandomK);
+        assert_eq!(config.selection.k, 3);
+        assert_eq!(Config::default().selection.policy, SelectionPolicy::All);
+        assert!(toml::from_str::<Config>("[selection]\npolicy = \"slowest\"").is_err());
+    }
+}
@@ -0,0 +1,47 @@
+// CREATE NFT --edit: fills in a JSON template in $EDITOR, since long
+// descriptions don't fit the pipe syntax. The editor needs the terminal the REPL
+// reads commands from, so the CLI runs it from its stdin reader (which stops
+// reading until the editor exits) and passes the saved file to the node with
+// --template; the node keeps handling network events in the meantime.
+use crate::{NFTInfo, Result};
+use std::path::{Path, PathBuf};
+
+// Returns the CREATE NFT or CREATE DRAFT part of a line asking for the editor
+pub fn edit_command(line: &str) -> Option<&str> {
+    match line.trim().strip_suffix("--edit").map(str::trim_end) {
+        Some(create @ ("CREATE NFT" | "CREATE DRAFT")) => Some(create),
+        _ => None,
+    }
+}
+
+// Opens $EDITOR on a template and returns the saved file once it holds a record
+pub async fn edit_template(draft: bool) -> Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!("peercache-nft-{}.json", std::process::id()));
+    let template = NFTInfo {
+        draft,
+        ..Default::default()
+    };
+    std::fs::write(&path, serde_json::to_vec_pretty(&template)?)?;
+
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_owned());
+    let status = tokio::process::Command::new(&editor)
+        .arg(&path)
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", editor, status).into());
+    }
+    validate(&path).map_err(|e| format!("{} (edits kept in {})", e, path.display()))?;
+    Ok(path)
+}
+
+fn validate(path: &Path) -> Result<()> {
+    let nft_info: NFTInfo = serde_json::from_slice(&std::fs::read(path)?)?;
+    if nft_info.collection_name.trim().is_empty() || nft_info.owner.trim().is_empty() {
+        return Err("collection_name and owner must not be empty".into());
+    }
+    for attachment in &nft_info.attachments {
+        attachment.validate()?;
+    }
+    Ok(())
+}
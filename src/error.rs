@@ -0,0 +1,29 @@
+// Errors from running commands and from the event loop. They are reported and
+// the node keeps running; only startup errors (the boxed Result) stop it.
+// Failures of the boxed Result inside a command become Other.
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PeerCacheError {
+    #[error("unknown command: {0}")]
+    UnknownCommand(String),
+    #[error("invalid command: {0}")]
+    InvalidCommand(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("the {0} channel is closed")]
+    ChannelClosed(&'static str),
+    #[error("query failed: {0}")]
+    QueryFailed(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<Box<dyn std::error::Error + Send + Sync>> for PeerCacheError {
+    fn from(e: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        PeerCacheError::Other(e.to_string())
+    }
+}
@@ -0,0 +1,173 @@
+// Machine-readable NDJSON stream for wrappers that drive the REPL. Events are
+// written to a dedicated file descriptor so they never mix with the human
+// readable logs; the schema is documented in the README.
+use crate::trash::Trashed;
+use crate::{ListMode, NFTInfo};
+use log::error;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+use std::sync::Mutex;
+
+static SINK: OnceCell<Mutex<File>> = OnceCell::new();
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Request {
+        source: String,
+        mode: &'a ListMode,
+    },
+    Response {
+        // The query this response belongs to, None if it arrived too late
+        query: Option<u64>,
+        source: String,
+        mode: &'a ListMode,
+        data: &'a [NFTInfo],
+        // Responses are pieces of a query still in flight, see Final
+        partial: bool,
+    },
+    // Consolidated, deduplicated result of a network query once every peer
+    // answered or its deadline passed
+    Final {
+        query: u64,
+        mode: &'a ListMode,
+        timed_out: bool,
+        responders: usize,
+        expected: usize,
+        data: &'a [NFTInfo],
+        // Peers that returned each record, in the same order as data
+        sources: Vec<Vec<String>>,
+    },
+    // Records fetched from the DHT because no peer returned them
+    DhtRecords {
+        data: &'a [NFTInfo],
+    },
+    LocalList {
+        data: &'a [NFTInfo],
+    },
+    Created {
+        record: &'a NFTInfo,
+    },
+    Published {
+        collection_name: &'a str,
+        item_id: u32,
+    },
+    QueryResult {
+        columns: &'a [String],
+        rows: &'a [Vec<String>],
+    },
+    Updated {
+        record: &'a NFTInfo,
+    },
+    Deleted {
+        collection_name: &'a str,
+        item_id: u32,
+    },
+    // The records that can be restored (TRASH LIST)
+    Trash {
+        entries: &'a [Trashed],
+    },
+    Restored {
+        record: &'a NFTInfo,
+    },
+    // A new address the node accepts connections on
+    Listening {
+        address: String,
+    },
+    // Connections, request budgets and store size (STATUS)
+    Status(&'a Status),
+    Peers {
+        peers: Vec<String>,
+    },
+    // Ping round-trip times of the connected peers (PEER STATS)
+    PeerStats {
+        peers: Vec<PeerStats>,
+    },
+    // What a peer reported about itself over identify (SHOW PEER)
+    PeerInfo {
+        peer: String,
+        agent_version: &'a str,
+        protocol_version: &'a str,
+        protocols: &'a [String],
+        listen_addrs: Vec<String>,
+        observed_addr: String,
+    },
+    CommandError {
+        message: String,
+    },
+}
+
+// Round-trip times in milliseconds, None before the first successful ping
+#[derive(Debug, Serialize)]
+pub struct PeerStats {
+    pub peer: String,
+    pub last_rtt_ms: Option<f64>,
+    pub avg_rtt_ms: Option<f64>,
+    pub min_rtt_ms: Option<f64>,
+    pub max_rtt_ms: Option<f64>,
+    pub samples: usize,
+    pub failures: u32,
+    // How the peer answered our queries
+    pub avg_response_ms: Option<f64>,
+    pub responses: u32,
+    pub query_failures: u32,
+    // Circuit breaker: closed, open or half-open
+    pub breaker: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub peer_id: String,
+    pub listen_addrs: Vec<String>,
+    pub peers: usize,
+    pub established_incoming: u32,
+    pub established_outgoing: u32,
+    pub pending_incoming: u32,
+    pub pending_outgoing: u32,
+    pub inbound_requests: usize,
+    pub max_inbound_requests: usize,
+    pub pending_queries: usize,
+    pub max_pending_queries: usize,
+    pub records: usize,
+    // Hedge requests sent, and how many were answered first
+    pub hedged_requests: u64,
+    pub hedge_wins: u64,
+    // Set when most peers run a newer version or some speak another protocol
+    pub version_advisory: Option<String>,
+}
+
+// Raw descriptors are a Unix thing; elsewhere the event stream isn't available
+#[cfg(unix)]
+pub fn init(fd: i32) {
+    // SAFETY: the descriptor is opened by the parent process (e.g. `3>events.ndjson`)
+    // and handed to the node, which from here on is its only owner: nothing else
+    // in the process uses or closes it, and the File closes it at exit.
+    let file = unsafe { File::from_raw_fd(fd) };
+    if SINK.set(Mutex::new(file)).is_err() {
+        error!("json event stream already initialised");
+    }
+}
+
+pub fn emit(event: Event) {
+    if let Some(sink) = SINK.get() {
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("error serializing json event: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = sink
+            .lock()
+            .expect("can lock json event stream")
+            .write_all(&line)
+        {
+            error!("error writing json event: {}", e);
+        }
+    }
+}
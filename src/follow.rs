@@ -0,0 +1,312 @@
+// FOLLOW: keeps local copies of the collections created by another node (or of
+// everything an owner holds) in sync. Followed nodes are asked periodically;
+// sequenced collections only send what changed since the highest sequence
+// number we already have. The follow list is saved in the data directory.
+use crate::store::NftStore;
+use crate::{NFTInfo, Result};
+use libp2p::PeerId;
+use log::error;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const FOLLOWING_FILE: &str = "following.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Follow {
+    Creator(PeerId),
+    Owner(String),
+}
+
+impl fmt::Display for Follow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Follow::Creator(peer) => write!(f, "{}", peer),
+            Follow::Owner(owner) => write!(f, "OWNER {}", owner),
+        }
+    }
+}
+
+impl Follow {
+    // Parses "<peer id>" or "OWNER <owner>", the form Display writes
+    pub fn parse(args: &str) -> Option<Follow> {
+        match args.trim().strip_prefix("OWNER ") {
+            Some(owner) if !owner.trim().is_empty() => Some(Follow::Owner(owner.trim().to_owned())),
+            Some(_) => None,
+            None => args.trim().parse::<PeerId>().ok().map(Follow::Creator),
+        }
+    }
+
+    fn wants(&self, nft_info: &NFTInfo) -> bool {
+        match self {
+            // Only the followed node itself is asked, so unsequenced records are its
+            // own unless it mirrored them from someone else
+            Follow::Creator(peer) => match &nft_info.creator {
+                Some(creator) => creator == &peer.to_string(),
+                None => nft_info.mirrored_from.is_none(),
+            },
+            Follow::Owner(owner) => nft_info.owner.eq_ignore_ascii_case(owner),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Following {
+    follows: BTreeSet<Follow>,
+    // Where the list is saved; None (ephemeral nodes) keeps it in memory
+    path: Option<PathBuf>,
+}
+
+impl Following {
+    // Loads the saved follow list from data_dir, if there is one
+    pub fn load(data_dir: Option<&Path>) -> Result<Self> {
+        let mut following = Following::default();
+        if let Some(data_dir) = data_dir {
+            let path = data_dir.join(FOLLOWING_FILE);
+            if path.exists() {
+                following.follows = serde_json::from_slice::<Vec<String>>(&fs::read(&path)?)?
+                    .iter()
+                    .map(|f| {
+                        Follow::parse(f)
+                            .ok_or_else(|| format!("invalid follow '{}' in {}", f, path.display()))
+                    })
+                    .collect::<std::result::Result<_, _>>()?;
+            }
+            following.path = Some(path);
+        }
+        Ok(following)
+    }
+
+    pub fn contains(&self, follow: &Follow) -> bool {
+        self.follows.contains(follow)
+    }
+
+    // Returns false if it was already followed
+    pub fn insert(&mut self, follow: Follow) -> bool {
+        let added = self.follows.insert(follow);
+        if added {
+            self.save();
+        }
+        added
+    }
+
+    // Returns false if it wasn't followed
+    pub fn remove(&mut self, follow: &Follow) -> bool {
+        let removed = self.follows.remove(follow);
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.follows.is_empty()
+    }
+
+    pub fn list(&self) -> Vec<Follow> {
+        self.follows.iter().cloned().collect()
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path, &self.follows) {
+                error!("error saving follow list to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn write(path: &Path, follows: &BTreeSet<Follow>) -> Result<()> {
+    let follows = follows.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&follows)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Highest sequence number held locally per collection created by the peer
+pub fn synced_collections(store: &NftStore, peer: &PeerId) -> Vec<(String, u64)> {
+    let creator = peer.to_string();
+    let records = store.read();
+    let collections = records
+        .iter()
+        .filter(|r| r.creator.as_ref() == Some(&creator))
+        .map(|r| r.collection_name.clone())
+        .collect::<BTreeSet<_>>();
+    collections
+        .into_iter()
+        .map(|collection_name| {
+            let seq = records
+                .iter()
+                .filter(|r| r.collection_name == collection_name)
+                .filter_map(|r| r.seq)
+                .max()
+                .unwrap_or(0);
+            (collection_name, seq)
+        })
+        .collect()
+}
+
+// Copies the followed records into the local store and returns the collections
+// that changed. Our own records, drafts and burned records are never replaced.
+pub fn mirror(
+    store: &NftStore,
+    local: &PeerId,
+    follow: &Follow,
+    records: &[NFTInfo],
+) -> BTreeSet<String> {
+    let mut changed = BTreeSet::new();
+    let mut local_records = store.write();
+    // Only a followed node answers for itself; owners are looked up on every peer
+    let source = match follow {
+        Follow::Creator(peer) => Some(peer),
+        Follow::Owner(_) => None,
+    };
+    for record in records.iter().filter(|r| follow.wants(r)) {
+        if let Err(e) = record.verify_burn(source) {
+            error!(
+                "not mirroring {}|{} from {}: {}",
+                record.collection_name, record.item_id, follow, e
+            );
+            continue;
+        }
+        let mut record = record.clone();
+        record.mirrored_from = Some(follow.to_string());
+        let existing = local_records
+            .iter_mut()
+            .find(|r| r.collection_name == record.collection_name && r.item_id == record.item_id);
+        let collection_name = record.collection_name.clone();
+        match existing {
+            None => local_records.push(record),
+            Some(existing)
+                if is_local(existing, local) || existing.draft || existing.burn.is_some() =>
+            {
+                continue
+            }
+            Some(existing) if record.seq > existing.seq => *existing = record,
+            // Without sequence numbers the followed copy is taken as it is
+            Some(existing) if record.seq.is_none() && existing.seq.is_none() => {
+                if *existing == record {
+                    continue;
+                }
+                *existing = record;
+            }
+            Some(_) => continue,
+        }
+        changed.insert(collection_name);
+    }
+    if !changed.is_empty() {
+        store.flush(&local_records);
+    }
+    changed
+}
+
+// Created (or sequenced) on this node rather than mirrored from another one
+pub fn is_local(nft_info: &NFTInfo, local: &PeerId) -> bool {
+    let local = local.to_string();
+    nft_info.mirrored_from.is_none() && nft_info.creator.as_ref().map_or(true, |c| c == &local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BurnStatement;
+    use libp2p::identity::Keypair;
+
+    fn nft(item_id: u32, description: &str) -> NFTInfo {
+        NFTInfo {
+            collection_name: "apes".to_owned(),
+            item_id,
+            description: description.to_owned(),
+            owner: "alice".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn burned_by(keys: &Keypair, item_id: u32) -> NFTInfo {
+        NFTInfo {
+            burn: Some(BurnStatement::sign(keys, "apes", item_id).unwrap()),
+            ..nft(item_id, "burned")
+        }
+    }
+
+    #[test]
+    fn mirrors_followed_records() {
+        let (local, peer) = (PeerId::random(), PeerId::random());
+        let store = NftStore::new(vec![nft(1, "ours")]);
+        let follow = Follow::Creator(peer);
+        let changed = mirror(&store, &local, &follow, &[nft(1, "theirs"), nft(2, "new")]);
+        assert_eq!(changed.into_iter().collect::<Vec<_>>(), vec!["apes"]);
+
+        let records = store.read();
+        // Our own record is never replaced
+        assert_eq!(records[0], nft(1, "ours"));
+        assert_eq!(records[1].description, "new");
+        assert_eq!(records[1].mirrored_from, Some(peer.to_string()));
+        drop(records);
+
+        // Nothing changed the second time
+        assert!(mirror(&store, &local, &follow, &[nft(2, "new")]).is_empty());
+    }
+
+    #[test]
+    fn only_mirrors_burns_signed_by_the_followed_node() {
+        let (followed, other) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let follow = Follow::Creator(PeerId::from(followed.public()));
+        let store = NftStore::default();
+        let local = PeerId::random();
+
+        assert!(mirror(&store, &local, &follow, &[burned_by(&other, 1)]).is_empty());
+        assert!(store.read().is_empty());
+        assert_eq!(
+            mirror(&store, &local, &follow, &[burned_by(&followed, 1)]).len(),
+            1
+        );
+        assert!(store.read()[0].burn.is_some());
+    }
+
+    #[test]
+    fn never_mirrors_unsequenced_burns_of_an_owner() {
+        let keys = Keypair::generate_ed25519();
+        let follow = Follow::Owner("alice".to_owned());
+        let store = NftStore::new(vec![NFTInfo {
+            mirrored_from: Some(follow.to_string()),
+            ..nft(1, "held")
+        }]);
+        // Any peer answering the owner query could have signed it
+        assert!(mirror(&store, &PeerId::random(), &follow, &[burned_by(&keys, 1)]).is_empty());
+        assert!(store.read()[0].burn.is_none());
+    }
+
+    #[test]
+    fn parses_what_it_displays() {
+        let creator = Follow::Creator(PeerId::random());
+        let owner = Follow::Owner("alice".to_owned());
+        assert_eq!(Follow::parse(&creator.to_string()), Some(creator));
+        assert_eq!(Follow::parse(&owner.to_string()), Some(owner));
+        assert_eq!(Follow::parse("OWNER "), None);
+        assert_eq!(Follow::parse("not-a-peer-id"), None);
+    }
+
+    #[test]
+    fn saves_the_follow_list() {
+        let dir = std::env::temp_dir().join(format!("peercache-follow-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let (creator, owner) = (
+            Follow::Creator(PeerId::random()),
+            Follow::Owner("bob".to_owned()),
+        );
+
+        let mut following = Following::load(Some(&dir)).unwrap();
+        assert!(following.insert(creator.clone()));
+        assert!(following.insert(owner.clone()));
+        assert!(!following.insert(owner.clone()));
+        assert!(following.remove(&creator));
+
+        let following = Following::load(Some(&dir)).unwrap();
+        assert_eq!(following.list(), vec![owner]);
+        assert!(!following.contains(&creator));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+// genesis.json: an initial dataset shipped alongside the binary. Every record
+// carries its creator's public key and a signature over the record; nodes only
+// import entries signed by the key configured as genesis_key, since the file
+// could otherwise be signed by anyone. Only a fixed set of fields is signed, so
+// fields added to NFTInfo later don't invalidate existing files. Applied
+// entries are remembered in the data directory, so a record deleted after its
+// import isn't imported again on the next start.
+use crate::store::NftStore;
+use crate::{NFTInfo, Result};
+use libp2p::{identity, PeerId};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const APPLIED_FILE: &str = "genesis_applied.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GenesisFile {
+    pub records: Vec<GenesisEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GenesisEntry {
+    pub record: NFTInfo,
+    // hex of the protobuf-encoded libp2p public key
+    pub creator: String,
+    // hex of the creator's signature over signed_payload(record)
+    pub signature: String,
+}
+
+impl GenesisEntry {
+    pub fn sign(record: NFTInfo, keys: &identity::Keypair) -> Result<Self> {
+        let signature = keys.sign(&signed_payload(&record)?)?;
+        Ok(GenesisEntry {
+            record,
+            creator: hex::encode(keys.public().into_protobuf_encoding()),
+            signature: hex::encode(signature),
+        })
+    }
+
+    pub fn verify(&self, trusted: &PeerId) -> Result<()> {
+        let creator = identity::PublicKey::from_protobuf_encoding(&hex::decode(&self.creator)?)?;
+        if &PeerId::from(creator.clone()) != trusted {
+            return Err(format!(
+                "signed by {}, not by the genesis key {}",
+                PeerId::from(creator),
+                trusted
+            )
+            .into());
+        }
+        let signature = hex::decode(&self.signature)?;
+        if !creator.verify(&signed_payload(&self.record)?, &signature) {
+            return Err("creator signature does not match record".into());
+        }
+        self.record.verify_burn(Some(&PeerId::from(creator)))
+    }
+}
+
+// The signed fields of a record, as a JSON array in a fixed order
+fn signed_payload(record: &NFTInfo) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&(
+        "genesis",
+        &record.collection_name,
+        record.item_id,
+        &record.description,
+        &record.owner,
+        &record.attachments,
+        &record.related,
+        &record.burn,
+        record.seq,
+        &record.creator,
+    ))?)
+}
+
+pub fn load(path: &Path) -> Result<GenesisFile> {
+    let bytes = fs::read(path)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+pub fn write(path: &Path, genesis: &GenesisFile) -> Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(genesis)?)?;
+    Ok(())
+}
+
+// Imports the entries of a genesis file signed by the trusted key that weren't
+// applied on a previous start. Records already in the store are left alone.
+pub fn import(store: &NftStore, path: &Path, trusted: Option<&PeerId>, data_dir: Option<&Path>) {
+    if !path.exists() {
+        return;
+    }
+    let trusted = match trusted {
+        Some(trusted) => trusted,
+        None => {
+            error!(
+                "ignoring genesis file {}: set genesis_key to the peer id that signed it",
+                path.display()
+            );
+            return;
+        }
+    };
+    let genesis = match load(path) {
+        Ok(genesis) => genesis,
+        Err(e) => {
+            error!("error loading genesis file {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let mut applied = match Applied::load(data_dir) {
+        Ok(applied) => applied,
+        Err(e) => {
+            error!("error loading applied genesis entries: {}", e);
+            return;
+        }
+    };
+    let mut records = store.write();
+    let (mut imported, mut skipped) = (0, 0);
+    for entry in genesis.records {
+        if applied.contains(&entry) {
+            skipped += 1;
+            continue;
+        }
+        if let Err(e) = entry.verify(trusted) {
+            error!(
+                "rejecting genesis record {}|{}: {}",
+                entry.record.collection_name, entry.record.item_id, e
+            );
+            continue;
+        }
+        applied.insert(&entry);
+        if records.iter().any(|r| {
+            r.collection_name == entry.record.collection_name && r.item_id == entry.record.item_id
+        }) {
+            skipped += 1;
+        } else {
+            records.push(entry.record);
+            imported += 1;
+        }
+    }
+    if imported > 0 {
+        store.flush(&records);
+    }
+    applied.save();
+    info!(
+        "Imported {} genesis records ({} already applied or present)",
+        imported, skipped
+    );
+}
+
+// Signatures of the genesis entries applied so far
+#[derive(Debug, Default)]
+struct Applied {
+    signatures: BTreeSet<String>,
+    // Where they are saved; None (ephemeral nodes) keeps them in memory
+    path: Option<PathBuf>,
+    changed: bool,
+}
+
+impl Applied {
+    fn load(data_dir: Option<&Path>) -> Result<Self> {
+        let mut applied = Applied::default();
+        if let Some(data_dir) = data_dir {
+            let path = data_dir.join(APPLIED_FILE);
+            if path.exists() {
+                applied.signatures = serde_json::from_slice(&fs::read(&path)?)
+                    .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+            }
+            applied.path = Some(path);
+        }
+        Ok(applied)
+    }
+
+    fn contains(&self, entry: &GenesisEntry) -> bool {
+        self.signatures.contains(&entry.signature)
+    }
+
+    fn insert(&mut self, entry: &GenesisEntry) {
+        self.changed |= self.signatures.insert(entry.signature.clone());
+    }
+
+    fn save(&self) {
+        if let (Some(path), true) = (&self.path, self.changed) {
+            if let Err(e) = write_applied(path, &self.signatures) {
+                error!("error saving {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn write_applied(path: &Path, signatures: &BTreeSet<String>) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(signatures)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nft(item_id: u32) -> NFTInfo {
+        NFTInfo {
+            collection_name: "apes".to_owned(),
+            item_id,
+            description: "ape".to_owned(),
+            owner: "alice".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_trusts_entries_signed_by_the_genesis_key() {
+        let (keys, other) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let trusted = PeerId::from(keys.public());
+        let entry = GenesisEntry::sign(nft(1), &keys).unwrap();
+        assert!(entry.verify(&trusted).is_ok());
+        assert!(entry.verify(&PeerId::from(other.public())).is_err());
+        // A consistent file signed by someone else
+        assert!(GenesisEntry::sign(nft(1), &other)
+            .unwrap()
+            .verify(&trusted)
+            .is_err());
+
+        let tampered = GenesisEntry {
+            record: NFTInfo {
+                owner: "mallory".to_owned(),
+                ..nft(1)
+            },
+            ..entry
+        };
+        assert!(tampered.verify(&trusted).is_err());
+    }
+
+    #[test]
+    fn imports_each_entry_once() {
+        let dir = std::env::temp_dir().join(format!("peercache-genesis-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let keys = identity::Keypair::generate_ed25519();
+        let trusted = PeerId::from(keys.public());
+        let path = dir.join("genesis.json");
+        let records = vec![
+            GenesisEntry::sign(nft(1), &keys).unwrap(),
+            GenesisEntry::sign(nft(2), &keys).unwrap(),
+        ];
+        write(&path, &GenesisFile { records }).unwrap();
+
+        let store = NftStore::default();
+        import(&store, &path, None, Some(&dir));
+        assert!(store.read().is_empty());
+        import(&store, &path, Some(&trusted), Some(&dir));
+        assert_eq!(*store.read(), vec![nft(1), nft(2)]);
+
+        // A deleted record stays deleted after a restart
+        store.delete(&PeerId::random(), "apes", 1).unwrap();
+        import(&store, &path, Some(&trusted), Some(&dir));
+        assert_eq!(*store.read(), vec![nft(2)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+// Node identity kept on disk, so the peer id survives restarts. The file holds
+// the keypair in the libp2p PrivateKey protobuf encoding; only ed25519 keys are
+// supported. Swarm keys for private networks use the go-libp2p text format.
+use crate::Result;
+use libp2p::identity::{ed25519, Keypair};
+use libp2p::pnet::PreSharedKey;
+use log::info;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// PrivateKey { Type = Ed25519 (field 1), Data = 64 bytes (field 2) }
+const ED25519_HEADER: [u8; 4] = [0x08, 0x01, 0x12, 0x40];
+
+pub fn load_or_create(path: &Path) -> Result<Keypair> {
+    if path.exists() {
+        let keypair = decode(&fs::read(path)?)
+            .map_err(|e| format!("invalid identity file {}: {}", path.display(), e))?;
+        info!("Loaded node identity from {}", path.display());
+        Ok(keypair)
+    } else {
+        let keypair = create(path)?;
+        info!("Saved new node identity to {}", path.display());
+        Ok(keypair)
+    }
+}
+
+// Generates a keypair and writes it to a file that must not exist yet
+pub fn create(path: &Path) -> Result<Keypair> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let keypair = ed25519::Keypair::generate();
+    write_new(path, &encode(&keypair))?;
+    Ok(Keypair::Ed25519(keypair))
+}
+
+// Generates a pre-shared key for a private network and writes it to a file that
+// must not exist yet; every node of the network needs a copy
+pub fn create_swarm_key(path: &Path) -> Result<PreSharedKey> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let key = PreSharedKey::new(rand::random());
+    write_new(path, key.to_string().as_bytes())?;
+    Ok(key)
+}
+
+pub fn load_swarm_key(path: &Path) -> Result<PreSharedKey> {
+    fs::read_to_string(path)?
+        .parse::<PreSharedKey>()
+        .map_err(|e| format!("invalid swarm key file {}: {}", path.display(), e).into())
+}
+
+fn write_new(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // Readable by the owner only, the file holds a secret key. Elsewhere the
+    // file gets the default permissions of its directory.
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    Ok(())
+}
+
+pub fn encode(keypair: &ed25519::Keypair) -> Vec<u8> {
+    let mut bytes = ED25519_HEADER.to_vec();
+    bytes.extend_from_slice(&keypair.encode());
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Keypair> {
+    if bytes.len() != ED25519_HEADER.len() + 64 || !bytes.starts_with(&ED25519_HEADER) {
+        return Err("not a protobuf-encoded ed25519 keypair".into());
+    }
+    let mut data = bytes[ED25519_HEADER.len()..].to_vec();
+    Ok(Keypair::Ed25519(ed25519::Keypair::decode(&mut data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let keypair = ed25519::Keypair::generate();
+        let encoded = encode(&keypair);
+        assert!(encoded.starts_with(&ED25519_HEADER));
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(
+            PeerId::from(decoded.public()),
+            PeerId::from(Keypair::Ed25519(keypair).public())
+        );
+    }
+
+    #[test]
+    fn rejects_other_contents() {
+        let encoded = encode(&ed25519::Keypair::generate());
+        assert!(decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(decode(&encoded[ED25519_HEADER.len()..]).is_err());
+        let mut wrong_header = encoded.clone();
+        wrong_header[0] ^= 0xff;
+        assert!(decode(&wrong_header).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("peercache-key-{}", rand::random::<u64>()));
+        write_new(&path, b"secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Existing files are never overwritten
+        assert!(write_new(&path, b"other").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+// Node identity kept in the Secret Service keyring (GNOME Keyring, KWallet)
+// instead of a plaintext file, through libsecret's secret-tool. The entry is
+// named after the identity file: an existing file is moved into the keyring on
+// first use, and where there is no keyring (headless servers, no secret-tool)
+// the file is used as before.
+use crate::{keyfile, Result};
+use libp2p::identity::{ed25519, Keypair};
+use log::{info, warn};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+const SECRET_TOOL: &str = "secret-tool";
+const SERVICE: &str = "peercache";
+
+pub fn load_or_create(path: &Path) -> Result<Keypair> {
+    let entry = path.display().to_string();
+    match lookup(&entry) {
+        Ok(Some(keypair)) => {
+            info!("Loaded node identity {} from the keyring", entry);
+            return Ok(keypair);
+        }
+        Ok(None) => (),
+        Err(e) => {
+            warn!("keyring unavailable ({}), using the identity file", e);
+            return keyfile::load_or_create(path);
+        }
+    }
+
+    let migrate = path.exists();
+    let encoded = match migrate {
+        true => fs::read(path)?,
+        false => keyfile::encode(&ed25519::Keypair::generate()),
+    };
+    let keypair = keyfile::decode(&encoded)
+        .map_err(|e| format!("invalid identity file {}: {}", path.display(), e))?;
+    if let Err(e) = store(&entry, &encoded) {
+        warn!("can't save to the keyring ({}), using the identity file", e);
+        return keyfile::load_or_create(path);
+    }
+    if migrate {
+        // The file only goes once the keyring gives the same key back
+        match lookup(&entry) {
+            Ok(Some(stored)) if stored.public() == keypair.public() => {
+                fs::remove_file(path)?;
+                info!("Moved node identity {} into the keyring", entry);
+            }
+            _ => warn!(
+                "can't read the same identity back from the keyring, keeping {}",
+                entry
+            ),
+        }
+    } else {
+        info!("Saved new node identity {} to the keyring", entry);
+    }
+    Ok(keypair)
+}
+
+fn lookup(entry: &str) -> Result<Option<Keypair>> {
+    let output = Command::new(SECRET_TOOL)
+        .args(["lookup", "service", SERVICE, "identity", entry])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("can't run {}: {}", SECRET_TOOL, e))?;
+    parse_lookup(&output)
+}
+
+// secret-tool exits with an error and prints nothing when there is no such entry
+fn parse_lookup(output: &Output) -> Result<Option<Keypair>> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match (output.status.success(), stdout.trim()) {
+        (true, secret) if !secret.is_empty() => Ok(Some(keyfile::decode(&hex::decode(secret)?)?)),
+        _ if stderr.trim().is_empty() => Ok(None),
+        _ => Err(stderr.trim().to_owned().into()),
+    }
+}
+
+fn store(entry: &str, encoded: &[u8]) -> Result<()> {
+    let mut child = Command::new(SECRET_TOOL)
+        .args(["store", "--label", &format!("peercache identity {}", entry)])
+        .args(["service", SERVICE, "identity", entry])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't run {}: {}", SECRET_TOOL, e))?;
+    // The secret is read from stdin, so it never shows up in the process list
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(hex::encode(encoded).as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_owned()
+            .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+
+    #[cfg(unix)]
+    fn output(code: i32, stdout: &str, stderr: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        Output {
+            // Wait statuses keep the exit code in the second byte
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_lookups() {
+        let keypair = ed25519::Keypair::generate();
+        let peer_id = PeerId::from(Keypair::Ed25519(keypair.clone()).public());
+        let found = output(
+            0,
+            &format!("{}\n", hex::encode(keyfile::encode(&keypair))),
+            "",
+        );
+        let found = parse_lookup(&found).unwrap().unwrap();
+        assert_eq!(PeerId::from(found.public()), peer_id);
+
+        assert!(parse_lookup(&output(1, "", "")).unwrap().is_none());
+        let unavailable = output(
+            1,
+            "",
+            "secret-tool: Cannot autolaunch D-Bus without X11 $DISPLAY",
+        );
+        assert!(parse_lookup(&unavailable).is_err());
+        assert!(parse_lookup(&output(0, "not hex", "")).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+// Round-trip times measured by the ping behaviour, per peer. Only the last few
+// samples are kept, so the average follows changes in a peer's link.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+const SAMPLES: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct PeerLatency {
+    rtts: VecDeque<Duration>,
+    // Pings that timed out or failed since the last successful one
+    pub failures: u32,
+}
+
+impl PeerLatency {
+    pub fn record(&mut self, rtt: Duration) {
+        if self.rtts.len() == SAMPLES {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(rtt);
+        self.failures = 0;
+    }
+
+    pub fn last(&self) -> Option<Duration> {
+        self.rtts.back().copied()
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        let total = self.rtts.iter().sum::<Duration>();
+        (!self.rtts.is_empty()).then(|| total / self.rtts.len() as u32)
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    pub fn samples(&self) -> usize {
+        self.rtts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn empty_without_samples() {
+        let latency = PeerLatency::default();
+        assert_eq!(latency.last(), None);
+        assert_eq!(latency.average(), None);
+        assert_eq!(latency.min(), None);
+        assert_eq!(latency.max(), None);
+        assert_eq!(latency.samples(), 0);
+    }
+
+    #[test]
+    fn summarises_samples() {
+        let mut latency = PeerLatency::default();
+        for rtt in [30, 10, 20] {
+            latency.record(ms(rtt));
+        }
+        assert_eq!(latency.last(), Some(ms(20)));
+        assert_eq!(latency.average(), Some(ms(20)));
+        assert_eq!(latency.min(), Some(ms(10)));
+        assert_eq!(latency.max(), Some(ms(30)));
+        assert_eq!(latency.samples(), 3);
+    }
+
+    #[test]
+    fn keeps_only_the_last_samples() {
+        let mut latency = PeerLatency::default();
+        latency.record(ms(1000));
+        for _ in 0..SAMPLES {
+            latency.record(ms(10));
+        }
+        assert_eq!(latency.samples(), SAMPLES);
+        assert_eq!(latency.max(), Some(ms(10)));
+        assert_eq!(latency.average(), Some(ms(10)));
+    }
+
+    #[test]
+    fn a_sample_resets_failures() {
+        let mut latency = PeerLatency {
+            failures: 3,
+            ..Default::default()
+        };
+        latency.record(ms(5));
+        assert_eq!(latency.failures, 0);
+    }
+}
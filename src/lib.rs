@@ -0,0 +1,317 @@
+// PeerCache as a library: a node (PeerCacheNode) with its libp2p behaviour,
+// NFT query protocol, local store and REPL commands. main.rs is a thin CLI
+// wrapper around it.
+mod access;
+mod behaviour;
+mod breaker;
+mod commands;
+pub mod config;
+pub mod editor;
+pub mod error;
+pub mod events;
+mod follow;
+mod genesis;
+pub mod keyfile;
+mod keyring;
+mod latency;
+mod local_only;
+pub mod logging;
+mod namespace;
+mod node;
+mod protocol;
+mod query;
+mod responder;
+mod selection;
+mod slowlog;
+mod sql;
+mod storage;
+pub mod store;
+mod topology;
+mod transport;
+mod trash;
+mod version;
+
+pub use error::PeerCacheError;
+pub use node::{NodeConfig, PeerCacheNode};
+
+use events::Event;
+use libp2p::{identity, PeerId};
+use local_only::LocalOnly;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use store::NftStore;
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
+pub type NFTInfoList = Vec<NFTInfo>;
+
+const GENESIS_FILE: &str = "genesis.json";
+pub const DEFAULT_DATA_DIR: &str = "peercache-data";
+pub const DEFAULT_CONFIG_FILE: &str = "peercache.toml";
+const TOPOLOGY_INTERVAL: Duration = Duration::from_secs(30);
+// How often followed nodes and owners are synced
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(30);
+// Forget gossiped views from peers that missed a few announcement rounds
+const TOPOLOGY_TTL: Duration = Duration::from_secs(90);
+// Refresh the Kademlia routing table now and then, as recommended by the spec
+const DHT_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(300);
+// How long the swarm keeps running on shutdown to send the topic unsubscribe
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+const DHT_PROTOCOL_NAME: &str = "/peercache/kad/1.0.0";
+// Sent to peers with identify, together with the crate version as agent version
+const IDENTIFY_PROTOCOL_VERSION: &str = "/peercache/1.0.0";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NFTInfo {
+    pub collection_name: String,
+    pub item_id: u32,
+    pub description: String,
+    pub owner: String,
+    // Drafts stay local and can be overwritten freely until published
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentMeta>,
+    #[serde(default)]
+    pub related: Vec<RecordRef>,
+    // Set once the NFT is permanently retired; burned records are kept, never recreated
+    #[serde(default)]
+    pub burn: Option<BurnStatement>,
+    // Position in the change log of a sequenced collection, assigned by its creator node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    // Peer id of the node that sequenced the record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    // Set on records copied in by FOLLOW: the followed node or owner they came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirrored_from: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BurnStatement {
+    // hex of the protobuf-encoded public key of the node that burned the record
+    pub burned_by: String,
+    // hex signature over "burn:<collection_name>|<item_id>"
+    pub signature: String,
+}
+
+impl BurnStatement {
+    fn sign(keys: &identity::Keypair, collection_name: &str, item_id: u32) -> Result<Self> {
+        let statement = format!("burn:{}|{}", collection_name, item_id);
+        Ok(BurnStatement {
+            burned_by: hex::encode(keys.public().into_protobuf_encoding()),
+            signature: hex::encode(keys.sign(statement.as_bytes())?),
+        })
+    }
+
+    // Returns the peer id of the node that signed the burn
+    fn verify(&self, collection_name: &str, item_id: u32) -> Result<PeerId> {
+        let statement = format!("burn:{}|{}", collection_name, item_id);
+        let burned_by =
+            identity::PublicKey::from_protobuf_encoding(&hex::decode(&self.burned_by)?)?;
+        if !burned_by.verify(statement.as_bytes(), &hex::decode(&self.signature)?) {
+            return Err("burn signature does not match the statement".into());
+        }
+        Ok(PeerId::from(burned_by))
+    }
+}
+
+impl NFTInfo {
+    // Records from other peers may carry forged burns. A sequenced record can
+    // only be burned by the node that sequenced it; an unsequenced one has no
+    // known creator, so only by the node it came from directly (the followed
+    // node, or the signer of a genesis file), if any.
+    fn verify_burn(&self, source: Option<&PeerId>) -> Result<()> {
+        let burn = match &self.burn {
+            Some(burn) => burn,
+            None => return Ok(()),
+        };
+        let burned_by = burn.verify(&self.collection_name, self.item_id)?;
+        match (&self.creator, source) {
+            (Some(creator), _) if creator == &burned_by.to_string() => Ok(()),
+            (Some(creator), _) => {
+                Err(format!("burned by {}, not by its creator {}", burned_by, creator).into())
+            }
+            (None, Some(source)) if source == &burned_by => Ok(()),
+            (None, Some(source)) => {
+                Err(format!("burned by {}, not by {}, which sent it", burned_by, source).into())
+            }
+            (None, None) => Err(format!(
+                "burned by {}, but the record isn't sequenced so its creator is unknown",
+                burned_by
+            )
+            .into()),
+        }
+    }
+}
+
+// A typed link to another NFT, e.g. "derivative_of" or "part_of"
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RecordRef {
+    pub relation: String,
+    pub collection_name: String,
+    pub item_id: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AttachmentMeta {
+    pub mime_type: String,
+    pub byte_size: u64,
+    // hex-encoded sha256 of the attachment contents
+    pub sha256: String,
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+impl AttachmentMeta {
+    pub fn validate(&self) -> Result<()> {
+        match self.mime_type.split_once('/') {
+            Some((kind, subtype)) if !kind.is_empty() && !subtype.is_empty() => (),
+            _ => return Err(format!("invalid mime type '{}'", self.mime_type).into()),
+        }
+        if self.byte_size == 0 {
+            return Err("attachment byte_size must be greater than zero".into());
+        }
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid sha256 '{}'", self.sha256).into());
+        }
+        if self.width.is_some() != self.height.is_some() {
+            return Err("attachment width and height must be given together".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ListMode {
+    ALL,
+    Collection(String),
+    Owner(String),
+    // Records of a sequenced collection changed after the given sequence number
+    CollectionSince(String, u64),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ListRequest {
+    mode: ListMode,
+    // Number of the query on the requesting node, echoed back in the response.
+    // Peers running older versions leave it out, which reads as 0.
+    #[serde(default)]
+    request_id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ListResponse {
+    mode: ListMode,
+    data: NFTInfoList,
+    #[serde(default)]
+    request_id: u64,
+}
+
+fn report_command_error(message: String) {
+    error!("{}", message);
+    events::emit(Event::CommandError { message });
+}
+
+// A collection is sequenced by this node once it holds records of it sequenced
+// here. Every change to such a record moves it to the next sequence number, so
+// followers only need to ask for what changed after the highest one they have.
+fn next_seq(records: &[NFTInfo], collection_name: &str, local: &PeerId) -> Option<u64> {
+    let local = local.to_string();
+    records
+        .iter()
+        .filter(|r| r.collection_name == collection_name && r.creator.as_ref() == Some(&local))
+        .filter_map(|r| r.seq)
+        .max()
+        .map(|seq| seq + 1)
+}
+
+// Stamps a changed record with the sequence number from next_seq, if any
+fn set_seq(nft_info: &mut NFTInfo, seq: Option<u64>, local: &PeerId) {
+    if seq.is_some() {
+        nft_info.seq = seq;
+        nft_info.creator = Some(local.to_string());
+    }
+}
+
+// The subset of the local store that may be sent to other peers
+fn shareable_nft_info(store: &NftStore, local_only: &LocalOnly) -> NFTInfoList {
+    store
+        .read()
+        .iter()
+        .filter(|r| !r.draft && !local_only.contains(&r.collection_name))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn burned(keys: &identity::Keypair, creator: Option<&identity::Keypair>) -> NFTInfo {
+        NFTInfo {
+            collection_name: "apes".to_owned(),
+            item_id: 1,
+            burn: Some(BurnStatement::sign(keys, "apes", 1).unwrap()),
+            seq: creator.map(|_| 1),
+            creator: creator.map(|c| PeerId::from(c.public()).to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn burn_statements_name_their_signer() {
+        let keys = identity::Keypair::generate_ed25519();
+        let burn = BurnStatement::sign(&keys, "apes", 1).unwrap();
+        assert_eq!(burn.verify("apes", 1).unwrap(), PeerId::from(keys.public()));
+        assert!(burn.verify("apes", 2).is_err());
+        assert!(burn.verify("bears", 1).is_err());
+
+        // Claiming another key doesn't make the signature match
+        let other = identity::Keypair::generate_ed25519();
+        let forged = BurnStatement {
+            burned_by: hex::encode(other.public().into_protobuf_encoding()),
+            ..burn
+        };
+        assert!(forged.verify("apes", 1).is_err());
+    }
+
+    #[test]
+    fn sequenced_records_are_only_burned_by_their_creator() {
+        let (creator, other) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        assert!(burned(&creator, Some(&creator)).verify_burn(None).is_ok());
+        assert!(burned(&other, Some(&creator)).verify_burn(None).is_err());
+        // Whoever sent the record, the creator decides
+        let other_peer = PeerId::from(other.public());
+        assert!(burned(&other, Some(&creator))
+            .verify_burn(Some(&other_peer))
+            .is_err());
+    }
+
+    #[test]
+    fn unsequenced_records_are_only_burned_by_their_source() {
+        let (source, other) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let source_peer = PeerId::from(source.public());
+        assert!(burned(&source, None)
+            .verify_burn(Some(&source_peer))
+            .is_ok());
+        assert!(burned(&other, None)
+            .verify_burn(Some(&source_peer))
+            .is_err());
+        assert!(burned(&source, None).verify_burn(None).is_err());
+
+        let unburned = NFTInfo {
+            burn: None,
+            ..burned(&other, None)
+        };
+        assert!(unburned.verify_burn(None).is_ok());
+    }
+}
@@ -0,0 +1,130 @@
+// Collections that are stored and queryable locally but never shared with peers
+// (PRIVATE COLLECTION). Shared between the event loop and the responder workers,
+// and saved in the data directory so they stay private across restarts.
+use crate::Result;
+use log::error;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const LOCAL_ONLY_FILE: &str = "local_only_collections.json";
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalOnly {
+    collections: Arc<Mutex<BTreeSet<String>>>,
+    // Where the collections are saved; None (ephemeral nodes) keeps them in memory
+    path: Option<PathBuf>,
+}
+
+impl LocalOnly {
+    // Loads the saved collections from data_dir, if there is one
+    pub fn load(data_dir: Option<&Path>) -> Result<Self> {
+        let mut local_only = LocalOnly::default();
+        if let Some(data_dir) = data_dir {
+            let path = data_dir.join(LOCAL_ONLY_FILE);
+            if path.exists() {
+                let collections = serde_json::from_slice::<BTreeSet<String>>(&fs::read(&path)?)
+                    .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+                local_only.collections = Arc::new(Mutex::new(
+                    collections.iter().map(|c| c.to_lowercase()).collect(),
+                ));
+            }
+            local_only.path = Some(path);
+        }
+        Ok(local_only)
+    }
+
+    pub fn contains(&self, collection_name: &str) -> bool {
+        self.collections
+            .lock()
+            .expect("can lock local-only collections")
+            .contains(&collection_name.to_lowercase())
+    }
+
+    // Returns false if the collection was already local-only
+    pub fn insert(&self, collection_name: &str) -> bool {
+        let mut collections = self
+            .collections
+            .lock()
+            .expect("can lock local-only collections");
+        let added = collections.insert(collection_name.to_lowercase());
+        if added {
+            self.save(&collections);
+        }
+        added
+    }
+
+    // Returns false if the collection wasn't local-only
+    pub fn remove(&self, collection_name: &str) -> bool {
+        let mut collections = self
+            .collections
+            .lock()
+            .expect("can lock local-only collections");
+        let removed = collections.remove(&collection_name.to_lowercase());
+        if removed {
+            self.save(&collections);
+        }
+        removed
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.collections
+            .lock()
+            .expect("can lock local-only collections")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn save(&self, collections: &BTreeSet<String>) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path, collections) {
+                error!(
+                    "error saving local-only collections to {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+fn write(path: &Path, collections: &BTreeSet<String>) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(collections)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_case() {
+        let local_only = LocalOnly::default();
+        assert!(local_only.insert("Apes"));
+        assert!(local_only.contains("apes"));
+        assert!(!local_only.insert("APES"));
+        assert!(local_only.remove("apes"));
+        assert!(!local_only.contains("Apes"));
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let dir = std::env::temp_dir().join(format!("peercache-local-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let local_only = LocalOnly::load(Some(&dir)).unwrap();
+        local_only.insert("apes");
+        local_only.insert("bears");
+        // Clones share the same set, as the responder workers do
+        local_only.clone().remove("bears");
+        assert_eq!(local_only.list(), vec!["apes"]);
+
+        let reloaded = LocalOnly::load(Some(&dir)).unwrap();
+        assert_eq!(reloaded.list(), vec!["apes"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
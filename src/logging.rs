@@ -0,0 +1,95 @@
+// Logger with per-target levels that can be changed while the node runs
+// (LOG SET <target>=<level>). Targets without an override use the filter the
+// node was started with (--log-level or RUST_LOG).
+use crate::Result;
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use std::sync::RwLock;
+
+static LOGGER: OnceCell<RuntimeLogger> = OnceCell::new();
+
+struct RuntimeLogger {
+    // Decides what gets logged for targets without an override
+    base: Box<dyn Log>,
+    base_level: LevelFilter,
+    // Writes every record that passes the filters
+    sink: Box<dyn Log>,
+    overrides: RwLock<Vec<(String, LevelFilter)>>,
+}
+
+impl RuntimeLogger {
+    fn override_for(&self, target: &str) -> Option<LevelFilter> {
+        self.overrides
+            .read()
+            .expect("can read log overrides")
+            .iter()
+            .filter(|(prefix, _)| target_matches(target, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+    }
+}
+
+// "behaviour" matches peercache::behaviour, "gossipsub" matches libp2p_gossipsub::*
+fn target_matches(target: &str, prefix: &str) -> bool {
+    [
+        prefix.to_owned(),
+        format!("peercache::{}", prefix),
+        format!("libp2p_{}", prefix),
+    ]
+    .iter()
+    .any(|p| target == p || target.starts_with(&format!("{}::", p)))
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.override_for(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.base.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.sink.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.sink.flush();
+    }
+}
+
+pub fn init(filter: Option<&str>) {
+    let mut base = pretty_env_logger::formatted_builder();
+    if let Some(filter) = filter {
+        base.parse_filters(filter);
+    }
+    let base = base.build();
+    let base_level = base.filter();
+    let sink = pretty_env_logger::formatted_builder()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let logger = LOGGER.get_or_init(|| RuntimeLogger {
+        base: Box::new(base),
+        base_level,
+        sink: Box::new(sink),
+        overrides: RwLock::new(Vec::new()),
+    });
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(base_level);
+    }
+}
+
+// Sets the level for one target (module path prefix) until the node exits
+pub fn set_level(target: &str, level: LevelFilter) -> Result<()> {
+    let logger = LOGGER.get().ok_or("the runtime logger is not installed")?;
+    let mut overrides = logger.overrides.write().expect("can write log overrides");
+    overrides.retain(|(prefix, _)| prefix != target);
+    overrides.push((target.to_owned(), level));
+    let max = overrides
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(logger.base_level, LevelFilter::max);
+    log::set_max_level(max);
+    Ok(())
+}
@@ -1,347 +1,240 @@
-use libp2p::{
-    core::upgrade,
-    floodsub::{Floodsub, FloodsubEvent, Topic},
-    futures::StreamExt,
-    identity,
-    mdns::{Mdns, MdnsConfig, MdnsEvent},
-    mplex,
-    noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{NetworkBehaviourEventProcess, Swarm, SwarmBuilder, SwarmEvent},
-    tcp::TokioTcpConfig,
-    NetworkBehaviour, PeerId, Transport,
+use clap::{Parser, Subcommand};
+use libp2p::{Multiaddr, PeerId};
+use log::error;
+use peercache::config::{self, Config};
+use peercache::{
+    editor, keyfile, logging, NodeConfig, PeerCacheNode, Result, DEFAULT_CONFIG_FILE,
+    DEFAULT_DATA_DIR,
 };
-use log::{error, info};
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::{io::AsyncBufReadExt, sync::mpsc};
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
-type NFTInfoList = Vec<NFTInfo>;
 
-static KEYS: Lazy<identity::Keypair> = Lazy::new(|| identity::Keypair::generate_ed25519());
-static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
-static TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("nft_info"));
-static mut NFT_STORE: NFTInfoList = Vec::new();
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct NFTInfo {
-    collection_name: String,
-    item_id: u32,
-    description: String,
-    owner: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-enum ListMode {
-    ALL,
-    Collection(String),
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ListRequest {
-    mode: ListMode,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ListResponse {
-    mode: ListMode,
-    data: NFTInfoList,
-    receiver: String,
-}
-
-enum EventType {
-    Response(ListResponse),
-    Input(String),
-}
-
-#[derive(NetworkBehaviour)]
-struct NFTInfoBehaviour {
-    floodsub: Floodsub,
-    mdns: Mdns,
-    #[behaviour(ignore)]
-    response_sender: mpsc::UnboundedSender<ListResponse>,
-}
-
-impl NetworkBehaviourEventProcess<FloodsubEvent> for NFTInfoBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        match event {
-            FloodsubEvent::Message(msg) => {
-                if let Ok(resp) = serde_json::from_slice::<ListResponse>(&msg.data) {
-                    if resp.receiver == PEER_ID.to_string() {
-                        info!("Response from {}:", msg.source);
-                        resp.data.iter().for_each(|r| info!("{:?}", r));
-                    }
-                } else if let Ok(ref req) = serde_json::from_slice::<ListRequest>(&msg.data) {
-                    match &req.mode {
-                        ListMode::ALL => {
-                            info!("Received ALL req: {:?} from {:?}", req, msg.source);
-                            respond_with_all_nft_info(
-                                self.response_sender.clone(),
-                                msg.source.to_string(),
-                            );
-                        }
-                        ListMode::Collection(collection_name) => {
-                            info!("Received collection req: {:?} from {:?}", req, msg.source);
-                            respond_with_collection_nft_info(
-                                self.response_sender.clone(),
-                                msg.source.to_string(),
-                                collection_name.clone(),
-                            );
-                        }
-                    }
-                }
-            }
-            _ => (),
-        }
-    }
-}
-
-fn respond_with_collection_nft_info(
-    sender: mpsc::UnboundedSender<ListResponse>,
-    receiver: String,
-    collection_name: String,
-) {
-    tokio::spawn(async move {
-        let nft_info = read_local_nft_info().clone();
-        let resp_data = nft_info
-            .into_iter()
-            .filter(|r| r.collection_name.eq_ignore_ascii_case(&collection_name))
-            .collect::<Vec<_>>();
-        // If only the peer has any collection items, send them back to the message origin
-        if resp_data.len() > 0 {
-            let response = ListResponse {
-                mode: ListMode::Collection(collection_name),
-                receiver,
-                data: resp_data,
-            };
-            if let Err(e) = sender.send(response) {
-                error!("error sending response via channel, {}", e);
-            }
-        }
-    });
-}
-
-fn respond_with_all_nft_info(sender: mpsc::UnboundedSender<ListResponse>, receiver: String) {
-    tokio::spawn(async move {
-        let nft_info = read_local_nft_info().clone();
-        let resp = ListResponse {
-            mode: ListMode::ALL,
-            receiver,
-            data: nft_info,
+#[derive(Parser)]
+#[clap(name = "peercache", about = "A peer-to-peer NFT metadata cache")]
+struct Cli {
+    /// Log filter, e.g. `info` or `peercache=debug` (defaults to RUST_LOG)
+    #[clap(long, global = true)]
+    log_level: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start a node and read REPL commands from stdin
+    Run {
+        #[clap(flatten)]
+        node: NodeArgs,
+    },
+    /// Generate a new ed25519 identity file and print its peer id, or a swarm key
+    #[clap(alias = "genkey")]
+    Keygen {
+        /// File to write the key to (must not exist yet)
+        #[clap(long)]
+        out: PathBuf,
+        /// Generate a private network pre-shared key for --swarm-key instead
+        #[clap(long)]
+        swarm: bool,
+    },
+    /// Start a node, run a single command against the network and exit
+    Query {
+        #[clap(flatten)]
+        node: NodeArgs,
+        /// How long to discover peers before sending the command
+        #[clap(long, default_value = "2s", parse(try_from_str = config::parse_duration))]
+        warmup: Duration,
+        /// How long to wait for results before exiting
+        #[clap(long, default_value = "10s", parse(try_from_str = config::parse_duration))]
+        wait: Duration,
+        /// The command, in the REPL syntax (e.g. `GET NFT ALL`)
+        #[clap(required = true)]
+        command: Vec<String>,
+    },
+}
+
+// Flags left unset fall back to the config file, then to the built-in defaults
+#[derive(clap::Args)]
+struct NodeArgs {
+    /// Config file to read settings from [default: peercache.toml, if it exists]
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Address to listen on, can be given more than once [default: /ip4/0.0.0.0/tcp/0]
+    #[clap(long)]
+    listen: Vec<Multiaddr>,
+    /// <multiaddr>/p2p/<peer id> of a node to bootstrap the DHT from (can be given more than once)
+    #[clap(long)]
+    bootstrap: Vec<Multiaddr>,
+    /// Don't discover peers on the local network with mDNS
+    #[clap(long)]
+    no_mdns: bool,
+    /// Only accept connections from this peer (can be given more than once)
+    #[clap(long)]
+    allow: Vec<PeerId>,
+    /// Never accept connections from this peer (can be given more than once)
+    #[clap(long)]
+    block: Vec<PeerId>,
+    /// Directory the local store is persisted in [default: peercache-data]
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    /// Keypair file giving the node a stable peer id (created on first run)
+    #[clap(long)]
+    identity: Option<PathBuf>,
+    /// Keep the --identity key in the OS keyring (moving an existing file there)
+    #[clap(long)]
+    keyring: bool,
+    /// Pre-shared key file: only nodes with the same key can connect (see `keygen --swarm`)
+    #[clap(long)]
+    swarm_key: Option<PathBuf>,
+    /// Keep the store in memory only and join a unique test network (see --namespace)
+    #[clap(long)]
+    ephemeral: bool,
+    /// Prefix for topics and protocol names; nodes only talk to nodes using the same one
+    #[clap(long)]
+    namespace: Option<String>,
+    /// Also store published NFTs in the Kademlia DHT
+    #[clap(long)]
+    dht_records: bool,
+    /// Peer id whose signature genesis.json entries must carry to be imported
+    #[clap(long)]
+    genesis_key: Option<PeerId>,
+    /// Write every result and event as a JSON line to this file descriptor
+    #[cfg(unix)]
+    #[clap(long)]
+    json_events_fd: Option<i32>,
+}
+
+impl NodeArgs {
+    fn into_config(self) -> Result<NodeConfig> {
+        let file = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::load_or_default(Path::new(DEFAULT_CONFIG_FILE))?,
         };
-        if let Err(e) = sender.send(resp) {
-            error!("error sending response via channel, {}", e);
-        }
-    });
-}
-
-impl NetworkBehaviourEventProcess<MdnsEvent> for NFTInfoBehaviour {
-    fn inject_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(discovered_list) => {
-                for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
-                }
-            }
-            MdnsEvent::Expired(expired_list) => {
-                for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
-                    }
-                }
-            }
+        #[cfg(unix)]
+        if let Some(fd) = self.json_events_fd {
+            peercache::events::init(fd);
         }
+        let (allow, block, groups) = (file.allow()?, file.block()?, file.groups()?);
+        let genesis_key = file.genesis_key()?;
+        let defaults = NodeConfig::default();
+        Ok(NodeConfig {
+            listen: first_non_empty(self.listen, file.listen, defaults.listen),
+            // Bootstrap peers from both places are used
+            bootstrap: self.bootstrap.into_iter().chain(file.bootstrap).collect(),
+            mdns: !self.no_mdns && file.mdns.unwrap_or(defaults.mdns),
+            data_dir: self
+                .data_dir
+                .or(file.data_dir)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR)),
+            identity: self.identity.or(file.identity),
+            keyring: self.keyring || file.keyring.unwrap_or(defaults.keyring),
+            swarm_key: self.swarm_key.or(file.swarm_key),
+            ephemeral: self.ephemeral,
+            namespace: self.namespace.or(file.namespace),
+            dht_records: self.dht_records || file.dht_records.unwrap_or(defaults.dht_records),
+            genesis_key: self.genesis_key.or(genesis_key),
+            // Peers from both places are allowed (or blocked)
+            allow: self.allow.into_iter().chain(allow).collect(),
+            block: self.block.into_iter().chain(block).collect(),
+            groups,
+            gossip: file.gossip,
+            timeouts: file.timeouts,
+            connections: file.connections,
+            resources: file.resources,
+            selection: file.selection,
+            trash: file.trash,
+            ..defaults
+        })
     }
 }
 
-async fn create_new_nft_info(
-    collection_name: &str,
-    item_id: u32,
-    description: &str,
-    owner: &str,
-) -> Result<()> {
-    let mut local_nft_info = read_local_nft_info();
-
-    local_nft_info.push(NFTInfo {
-        collection_name: collection_name.to_owned(),
-        item_id: item_id.clone(),
-        description: description.to_owned(),
-        owner: owner.to_owned(),
-    });
-
-    info!("Created NFT info:");
-    info!("Name: {}", collection_name);
-    info!("Item ID: {}", item_id);
-    info!("NFT Item Description  {}", description);
-    info!("NFT Item owner {}", owner);
-
-    Ok(())
-}
-
-fn read_local_nft_info() -> &'static mut NFTInfoList {
-    // DANGEROUS and may cause errors in multithreaded environments; have to come up with a better solution.
-    return unsafe { &mut NFT_STORE };
+fn first_non_empty<T>(flags: Vec<T>, file: Vec<T>, default: Vec<T>) -> Vec<T> {
+    [flags, file]
+        .into_iter()
+        .find(|values| !values.is_empty())
+        .unwrap_or(default)
 }
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
-
-    info!("Peer Id: {}", PEER_ID.clone());
-    let (response_sender, mut response_rcv) = mpsc::unbounded_channel();
-
-    let auth_keys = Keypair::<X25519Spec>::new()
-        .into_authentic(&KEYS)
-        .expect("can create auth keys");
-
-    let transp = TokioTcpConfig::new()
-        .upgrade(upgrade::Version::V1)
-        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
-        .multiplex(mplex::MplexConfig::new())
-        .boxed();
-
-    let mut behaviour = NFTInfoBehaviour {
-        floodsub: Floodsub::new(PEER_ID.clone()),
-        mdns: Mdns::new(MdnsConfig::default())
-            .await
-            .expect("can create mdns"),
-        response_sender,
+    let cli = Cli::parse();
+    let filter = cli.log_level.or_else(|| std::env::var("RUST_LOG").ok());
+    logging::init(filter.as_deref());
+
+    let result = match cli.command {
+        Command::Run { node } => run(node).await,
+        Command::Keygen { out, swarm } => keygen(out, swarm),
+        Command::Query {
+            node,
+            warmup,
+            wait,
+            command,
+        } => query(node, warmup, wait, command.join(" ")).await,
     };
+    // A bad config file or a node that can't start is reported, not a panic
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    behaviour.floodsub.subscribe(TOPIC.clone());
-
-    let mut swarm = SwarmBuilder::new(transp, behaviour, PEER_ID.clone())
-        .executor(Box::new(|fut| {
-            tokio::spawn(fut);
-        }))
-        .build();
-
-    let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
-
-    Swarm::listen_on(
-        &mut swarm,
-        "/ip4/0.0.0.0/tcp/0"
-            .parse()
-            .expect("can get a local socket"),
-    )
-    .expect("swarm can be started");
-
-    loop {
-        let evt = {
-            tokio::select! {
-                line = stdin.next_line() => Some(EventType::Input(line.expect("can get line").expect("can read line from stdin"))),
-                response = response_rcv.recv() => Some(EventType::Response(response.expect("response exists"))),
-                // Commenting out the below event logs as it was creating a lot of clutter on the terminal screen
-                event = swarm.select_next_some() => match event {
-                    // SwarmEvent::NewListenAddr { address, .. } => {
-                    //     println!("Listening in {:?}", address);
-                    //     None
-                    // },
-                    // SwarmEvent::ConnectionEstablished { peer_id, endpoint, ..} => {
-                    //     info!("Connection established with {} at {:?}", peer_id, endpoint);
-                    //     None
-                    // }
-                    // SwarmEvent::ConnectionClosed {peer_id, endpoint, ..} => {
-                    //     info!("Connection closed with {} at {:?}", peer_id, endpoint);
-                    //     None
-                    // }
-                    // SwarmEvent::IncomingConnection {local_addr, send_back_addr} => {
-                    //     info!("Incoming connection from {:?}", send_back_addr);
-                    //     None
-                    // }
-                    // SwarmEvent::Dialing(PeerId) => {
-                    //     info!("Dialing peer {}", PeerId);
-                    //     None
-                    // }
-                    _ => {
-                        // info!("Unhandled Swarm Event: {:?}", event);
-                        None
-                    }
-                },
-            }
-        };
+async fn run(node: NodeArgs) -> Result<()> {
+    let mut node = PeerCacheNode::new(node.into_config()?).await?;
 
-        if let Some(event) = evt {
-            match event {
-                EventType::Response(resp) => {
-                    let json = serde_json::to_string(&resp).expect("can jsonify response");
-                    swarm
-                        .behaviour_mut()
-                        .floodsub
-                        .publish(TOPIC.clone(), json.as_bytes());
-                }
-                EventType::Input(line) => match line.as_str() {
-                    "LIST PEERS" => handle_list_peers(&mut swarm).await,
-                    cmd if cmd.starts_with("GET NFT") => {
-                        handle_list_nft_info(cmd, &mut swarm).await
+    // Feed stdin lines to the node as REPL commands
+    let (command_sender, command_rcv) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = stdin.next_line().await {
+            // The editor needs the terminal, so it runs here while stdin isn't being
+            // read; the node keeps running and gets the saved file
+            let line = match editor::edit_command(&line) {
+                Some(create) => match editor::edit_template(create == "CREATE DRAFT").await {
+                    Ok(path) => format!("{} --template {}", create, path.display()),
+                    Err(e) => {
+                        error!("error editing NFT: {}", e);
+                        continue;
                     }
-                    cmd if cmd.starts_with("CREATE NFT") => handle_create_nft_info(cmd).await,
-                    _ => error!("unknown command"),
                 },
+                None => line,
+            };
+            if command_sender.send(line).is_err() {
+                break;
             }
         }
+    });
+    node.run(command_rcv).await;
+    // The stdin reader would otherwise keep the runtime alive until the next line
+    std::process::exit(0);
+}
+
+fn keygen(out: PathBuf, swarm: bool) -> Result<()> {
+    let cant_write = |e| format!("can't write {}: {}", out.display(), e);
+    if swarm {
+        let key = keyfile::create_swarm_key(&out).map_err(cant_write)?;
+        println!("Swarm key fingerprint: {}", key.fingerprint());
+    } else {
+        let keypair = keyfile::create(&out).map_err(cant_write)?;
+        println!("Peer Id: {}", PeerId::from(keypair.public()));
     }
+    Ok(())
 }
 
-async fn handle_list_peers(swarm: &mut Swarm<NFTInfoBehaviour>) {
-    info!("Discovered Peers:");
-    let nodes = swarm.behaviour().mdns.discovered_nodes();
-    let mut unique_peers = HashSet::new();
-    for peer in nodes {
-        unique_peers.insert(peer);
-    }
-    unique_peers.iter().for_each(|p| info!("{}", p));
-}
-
-async fn handle_list_nft_info(cmd: &str, swarm: &mut Swarm<NFTInfoBehaviour>) {
-    let rest = cmd.strip_prefix("GET NFT ");
-    match rest {
-        Some("ALL") => {
-            let req = ListRequest {
-                mode: ListMode::ALL,
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .floodsub
-                .publish(TOPIC.clone(), json.as_bytes());
-        }
-        Some(collection_name) => {
-            let req = ListRequest {
-                mode: ListMode::Collection(collection_name.to_owned()),
-            };
-            let json = serde_json::to_string(&req).expect("can jsonify request");
-            swarm
-                .behaviour_mut()
-                .floodsub
-                .publish(TOPIC.clone(), json.as_bytes());
-        }
-        None => {
-            let v = read_local_nft_info();
-            info!("Local NFTInfo({})", v.len());
-            v.iter().for_each(|r| info!("{:?}", r));
-        }
-    }
-}
+async fn query(node: NodeArgs, warmup: Duration, wait: Duration, command: String) -> Result<()> {
+    let mut node = PeerCacheNode::new(node.into_config()?).await?;
 
-async fn handle_create_nft_info(cmd: &str) {
-    if let Some(rest) = cmd.strip_prefix("CREATE NFT ") {
-        let elements: Vec<&str> = rest.split("|").collect();
-        if elements.len() < 3 {
-            info!("too few arguments - Format: collection_name|item_id|description|owner");
-        } else {
-            let collection_name = elements.get(0).expect("collection name is present");
-            let item_id = elements
-                .get(1)
-                .expect("item id is present")
-                .parse::<u32>()
-                .expect("item id parse error");
-            let description = elements.get(2).expect("description is present");
-            let owner = elements.get(3).expect("owner name is present");
-            if let Err(e) = create_new_nft_info(collection_name, item_id, description, owner).await
-            {
-                error!("error creating NFT info: {}", e);
-            };
+    // The node stops once the sender is dropped after the wait
+    let (command_sender, command_rcv) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        tokio::time::sleep(warmup).await;
+        if command_sender.send(command).is_ok() {
+            tokio::time::sleep(wait).await;
         }
+    });
+    node.run(command_rcv).await;
+    // Scripts can tell from the exit status whether the results are complete
+    match node.take_failure() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
     }
 }
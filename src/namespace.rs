@@ -0,0 +1,41 @@
+// Separate networks (e.g. --ephemeral test networks) can share a LAN without
+// talking to each other: a node's namespace prefixes its gossipsub topics and
+// protocol names.
+use libp2p::gossipsub::IdentTopic as Topic;
+
+#[derive(Debug, Clone, Default)]
+pub struct Namespace(Option<String>);
+
+impl Namespace {
+    pub fn new(namespace: Option<String>) -> Self {
+        Namespace(namespace)
+    }
+
+    // Every node of the network joins this topic
+    pub fn topic(&self) -> Topic {
+        Topic::new(self.topic_name("nft_info"))
+    }
+
+    // Peers subscribe to the topic of every collection they host, so collection
+    // queries only go to peers that can answer them
+    pub fn collection_topic(&self, collection_name: &str) -> Topic {
+        Topic::new(self.topic_name(&format!("nft_info/{}", collection_name.to_lowercase())))
+    }
+
+    fn topic_name(&self, name: &str) -> String {
+        match &self.0 {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name.to_owned(),
+        }
+    }
+
+    // "/peercache/kad/1.0.0" becomes "/peercache/<namespace>/kad/1.0.0"
+    pub fn protocol(&self, name: &str) -> Vec<u8> {
+        match &self.0 {
+            Some(namespace) => name
+                .replacen("/peercache/", &format!("/peercache/{}/", namespace), 1)
+                .into_bytes(),
+            None => name.as_bytes().to_vec(),
+        }
+    }
+}
@@ -0,0 +1,549 @@
+// A PeerCache node: owns the swarm and the local store and drives the event
+// loop. Commands use the same syntax as the REPL.
+use crate::access::Access;
+use crate::behaviour::{NFTInfoBehaviour, PendingResponse};
+use crate::config::{
+    ConnectionConfig, GossipConfig, ResourceConfig, SelectionConfig, TimeoutConfig, TrashConfig,
+};
+use crate::error::PeerCacheError;
+use crate::events::{self, Event};
+use crate::follow::Following;
+use crate::local_only::LocalOnly;
+use crate::namespace::Namespace;
+use crate::store::NftStore;
+use crate::transport;
+use crate::trash::Trash;
+use crate::{
+    commands, genesis, keyfile, keyring, report_command_error, responder, shareable_nft_info,
+    storage, Result, DEFAULT_DATA_DIR, DHT_BOOTSTRAP_INTERVAL, FOLLOW_INTERVAL, GENESIS_FILE,
+    SHUTDOWN_GRACE, TOPOLOGY_INTERVAL,
+};
+use libp2p::{
+    futures::StreamExt,
+    gossipsub::IdentTopic as Topic,
+    identity,
+    swarm::{Swarm, SwarmBuilder, SwarmEvent},
+    Multiaddr, PeerId,
+};
+use log::{error, info};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub listen: Vec<Multiaddr>,
+    // <multiaddr>/p2p/<peer id> of nodes to bootstrap the DHT from
+    pub bootstrap: Vec<Multiaddr>,
+    // Discover peers on the local network
+    pub mdns: bool,
+    // Where the local store is persisted
+    pub data_dir: PathBuf,
+    // Keep the store in memory only and nothing on disk
+    pub ephemeral: bool,
+    // Prefix for topics and protocol names, so separate networks can share a LAN.
+    // Ephemeral nodes get a unique one when it isn't set.
+    pub namespace: Option<String>,
+    // Keypair file to load (or create on first run); None uses a fresh identity
+    pub identity: Option<PathBuf>,
+    // Keep the identity in the OS keyring, falling back to the file without one
+    pub keyring: bool,
+    // Pre-shared key file of a private network; None joins the public one
+    pub swarm_key: Option<PathBuf>,
+    // Mirror shareable records into the Kademlia DHT
+    pub dht_records: bool,
+    // Peer id whose signed genesis.json is imported; without it the file is ignored
+    pub genesis_key: Option<PeerId>,
+    // Only these peers may connect (when not empty)
+    pub allow: Vec<PeerId>,
+    // Peers that may never connect, in addition to the ones banned with BAN
+    pub block: Vec<PeerId>,
+    // Peer groups queries can be sent to with @<group>; GROUP SET adds more
+    pub groups: BTreeMap<String, BTreeSet<PeerId>>,
+    pub gossip: GossipConfig,
+    pub timeouts: TimeoutConfig,
+    pub connections: ConnectionConfig,
+    pub resources: ResourceConfig,
+    // Which hosts of a collection its queries are sent to
+    pub selection: SelectionConfig,
+    pub trash: TrashConfig,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            listen: vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid listen address")],
+            bootstrap: Vec::new(),
+            mdns: true,
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            ephemeral: false,
+            namespace: None,
+            identity: None,
+            keyring: false,
+            swarm_key: None,
+            dht_records: false,
+            genesis_key: None,
+            allow: Vec::new(),
+            block: Vec::new(),
+            groups: BTreeMap::new(),
+            gossip: GossipConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            connections: ConnectionConfig::default(),
+            resources: ResourceConfig::default(),
+            selection: SelectionConfig::default(),
+            trash: TrashConfig::default(),
+        }
+    }
+}
+
+enum EventType {
+    Response(PendingResponse),
+    Input(String),
+    Connected(PeerId),
+    Disconnected(PeerId),
+    TopologyTick,
+    FollowTick,
+    BootstrapTick,
+    QueryDeadline,
+    Shutdown,
+}
+
+pub struct PeerCacheNode {
+    swarm: Swarm<NFTInfoBehaviour>,
+    store: NftStore,
+    response_rcv: mpsc::Receiver<PendingResponse>,
+    // The last command that failed, see take_failure()
+    failure: Option<PeerCacheError>,
+}
+
+impl PeerCacheNode {
+    pub async fn new(config: NodeConfig) -> Result<Self> {
+        PeerCacheNode::build(config, false).await
+    }
+
+    // A node without any transport, for using PeerCache as a local cache: it
+    // keeps the store, commands and events of a networked node but never
+    // listens, dials or discovers peers, so network queries find nobody to ask.
+    // Switching to new() later enables networking without other changes.
+    pub async fn new_isolated(config: NodeConfig) -> Result<Self> {
+        let config = NodeConfig {
+            listen: Vec::new(),
+            bootstrap: Vec::new(),
+            mdns: false,
+            ..config
+        };
+        PeerCacheNode::build(config, true).await
+    }
+
+    async fn build(config: NodeConfig, isolated: bool) -> Result<Self> {
+        let keys = match (&config.identity, config.keyring) {
+            (Some(path), true) => keyring::load_or_create(path)?,
+            (Some(path), false) => keyfile::load_or_create(path)?,
+            (None, true) => return Err("the keyring needs --identity to name the key".into()),
+            (None, false) => identity::Keypair::generate_ed25519(),
+        };
+        let peer_id = PeerId::from(keys.public());
+        info!("Peer Id: {}", peer_id);
+        let namespace = match (&config.namespace, config.ephemeral) {
+            (Some(namespace), _) => Some(namespace.clone()),
+            (None, true) => {
+                let id = PeerId::random().to_string();
+                Some(format!("ephemeral-{}", &id[id.len() - 8..]))
+            }
+            (None, false) => None,
+        };
+        if let Some(namespace) = &namespace {
+            info!("Network namespace: {}", namespace);
+        }
+        let namespace = Namespace::new(namespace);
+        let store = if config.ephemeral {
+            // A store without a path is never written to disk
+            info!("Ephemeral node: the store is kept in memory and discarded at exit");
+            NftStore::default()
+        } else {
+            let (records, path) = storage::init(&config.data_dir)?;
+            let store = NftStore::persistent(records, path);
+            info!(
+                "Loaded {} NFTs from {}",
+                store.read().len(),
+                config.data_dir.display()
+            );
+            store
+        };
+        // Ephemeral nodes keep bans and local-only collections in memory
+        let data_dir = Some(config.data_dir.as_path()).filter(|_| !config.ephemeral);
+        let access = Access::new(&config.allow, &config.block, data_dir)?;
+        // Loaded before subscribing, so local-only collections aren't advertised
+        let local_only = LocalOnly::load(data_dir)?;
+        let following = Following::load(data_dir)?;
+        let trash = Trash::load(data_dir, config.trash.window)?;
+        genesis::import(
+            &store,
+            Path::new(GENESIS_FILE),
+            config.genesis_key.as_ref(),
+            data_dir,
+        );
+        // Workers wait for the event loop once this many responses are waiting to be sent
+        let (response_sender, response_rcv) =
+            mpsc::channel(config.resources.request_workers.max(1));
+        let (job_sender, job_rcv) = mpsc::channel(config.resources.max_inbound_requests.max(1));
+        responder::spawn_workers(
+            store.clone(),
+            local_only.clone(),
+            job_rcv,
+            response_sender,
+            &config.resources,
+        );
+
+        let transp = if isolated {
+            info!("Isolated node: networking is disabled");
+            transport::isolated()
+        } else {
+            let swarm_key = match &config.swarm_key {
+                Some(path) => {
+                    let key = keyfile::load_swarm_key(path)?;
+                    info!(
+                        "Private network, swarm key fingerprint {}",
+                        key.fingerprint()
+                    );
+                    Some(key)
+                }
+                None => None,
+            };
+            transport::build(&keys, &config.connections, swarm_key)?
+        };
+
+        let mut behaviour = NFTInfoBehaviour::new(
+            &config,
+            keys,
+            namespace.clone(),
+            store.clone(),
+            job_sender,
+            access,
+            local_only.clone(),
+        )
+        .await?;
+        behaviour.following = following;
+        behaviour.trash = trash;
+        behaviour
+            .gossipsub
+            .subscribe(&namespace.topic())
+            .map_err(|e| format!("can't subscribe to topic: {:?}", e))?;
+        let hosted = shareable_nft_info(&store, &local_only)
+            .into_iter()
+            .map(|r| r.collection_name.to_lowercase())
+            .collect::<BTreeSet<_>>();
+        for collection_name in hosted {
+            behaviour
+                .gossipsub
+                .subscribe(&namespace.collection_topic(&collection_name))
+                .map_err(|e| format!("can't subscribe to collection topic: {:?}", e))?;
+        }
+
+        let mut swarm = SwarmBuilder::new(transp, behaviour, peer_id)
+            .executor(Box::new(|fut| {
+                tokio::spawn(fut);
+            }))
+            .connection_limits(config.connections.limits())
+            .build();
+
+        for peer in swarm.behaviour().access.blocked() {
+            swarm.ban_peer_id(peer);
+            swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+        }
+
+        for addr in config.listen {
+            Swarm::listen_on(&mut swarm, addr)?;
+        }
+        for addr in config.bootstrap {
+            let peer_id = commands::add_bootstrap_peer(&mut swarm, addr)?;
+            info!("Bootstrapping DHT via {}", peer_id);
+        }
+
+        Ok(PeerCacheNode {
+            swarm,
+            store,
+            response_rcv,
+            failure: None,
+        })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    pub fn store(&self) -> &NftStore {
+        &self.store
+    }
+
+    pub async fn execute(&mut self, line: &str) -> std::result::Result<(), PeerCacheError> {
+        commands::execute(line, &self.store, &mut self.swarm).await
+    }
+
+    // Why the commands run so far didn't all succeed: a command error, a network
+    // query that not every peer answered (or nobody did), a failed DHT lookup or
+    // a query still waiting for answers. Clears it.
+    pub fn take_failure(&mut self) -> Option<PeerCacheError> {
+        let behaviour = self.swarm.behaviour_mut();
+        if let Some(e) = self.failure.take() {
+            return Some(e);
+        }
+        if let Some(message) = behaviour.query_failure.take() {
+            return Some(PeerCacheError::QueryFailed(message));
+        }
+        match behaviour.queries.pending_count() {
+            0 => None,
+            n => Some(PeerCacheError::QueryFailed(format!(
+                "{} queries were still waiting for answers",
+                n
+            ))),
+        }
+    }
+
+    // Leaves the topic and saves the store; the connections close when the node is dropped
+    pub async fn shutdown(&mut self) {
+        info!("Shutting down");
+        let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+        // Identity topics hash to their name, so the collection topics can be rebuilt from it
+        let topics = gossipsub
+            .topics()
+            .map(|hash| Topic::new(hash.as_str()))
+            .collect::<Vec<_>>();
+        for topic in topics {
+            if let Err(e) = gossipsub.unsubscribe(&topic) {
+                error!("error unsubscribing from {}: {:?}", topic, e);
+            }
+        }
+        self.store.flush(&self.store.read());
+        // Keep polling the swarm for a moment so the unsubscribe reaches our peers
+        let _ = tokio::time::timeout(SHUTDOWN_GRACE, async {
+            loop {
+                self.swarm.select_next_some().await;
+            }
+        })
+        .await;
+    }
+
+    // Drives the node until the command channel is closed, EXIT is entered or
+    // Ctrl-C is pressed, then shuts it down
+    pub async fn run(&mut self, mut command_rcv: mpsc::UnboundedReceiver<String>) {
+        let mut topology_tick = tokio::time::interval(TOPOLOGY_INTERVAL);
+        let mut bootstrap_tick = tokio::time::interval(DHT_BOOTSTRAP_INTERVAL);
+        let mut follow_tick = tokio::time::interval(FOLLOW_INTERVAL);
+        let ctrl_c = tokio::signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+
+        loop {
+            dial_discovered_peers(&mut self.swarm);
+            let next_deadline = self.swarm.behaviour().next_deadline();
+            let evt = {
+                tokio::select! {
+                    line = command_rcv.recv() => match line {
+                        Some(line) if line.trim() == "EXIT" => Some(EventType::Shutdown),
+                        Some(line) => Some(EventType::Input(line)),
+                        None => Some(EventType::Shutdown),
+                    },
+                    _ = &mut ctrl_c => Some(EventType::Shutdown),
+                    response = self.response_rcv.recv() => match response {
+                        Some(response) => Some(EventType::Response(response)),
+                        None => {
+                            error!("{}, stopping", PeerCacheError::ChannelClosed("response"));
+                            Some(EventType::Shutdown)
+                        }
+                    },
+                    _ = topology_tick.tick() => Some(EventType::TopologyTick),
+                    _ = follow_tick.tick() => Some(EventType::FollowTick),
+                    _ = bootstrap_tick.tick() => Some(EventType::BootstrapTick),
+                    _ = tokio::time::sleep_until(tokio::time::Instant::from_std(next_deadline.unwrap_or_else(Instant::now))), if next_deadline.is_some() => Some(EventType::QueryDeadline),
+                    event = self.swarm.select_next_some() => match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            Some(EventType::Connected(peer_id))
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                            Some(EventType::Disconnected(peer_id))
+                        }
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            // Peers can BOOTSTRAP from the printed address
+                            info!("Listening on {}/p2p/{}", address, self.swarm.local_peer_id());
+                            events::emit(Event::Listening { address: address.to_string() });
+                            None
+                        }
+                        SwarmEvent::ExpiredListenAddr { address, .. } => {
+                            info!("No longer listening on {}", address);
+                            None
+                        }
+                        SwarmEvent::ListenerError { error, .. } => {
+                            error!("listener error: {}", error);
+                            None
+                        }
+                        _ => None,
+                    },
+                }
+            };
+
+            if let Some(event) = evt {
+                match event {
+                    EventType::Response((channel, resp)) => {
+                        if self
+                            .swarm
+                            .behaviour_mut()
+                            .request_response
+                            .send_response(channel, resp)
+                            .is_err()
+                        {
+                            error!("error sending response: requesting peer is gone");
+                        }
+                    }
+                    EventType::Connected(peer) => {
+                        if !self.swarm.behaviour().access.is_allowed(&peer) {
+                            info!("Refusing {}: not on the allowlist", peer);
+                            // Banning drops the connections without a ConnectionClosed event
+                            self.swarm.ban_peer_id(peer);
+                            self.swarm.behaviour_mut().forget_peer(&peer);
+                        }
+                    }
+                    EventType::Disconnected(peer) => self.swarm.behaviour_mut().forget_peer(&peer),
+                    EventType::TopologyTick => commands::handle_topology_tick(&mut self.swarm),
+                    EventType::FollowTick => {
+                        commands::handle_follow_tick(&self.store, &mut self.swarm)
+                    }
+                    EventType::BootstrapTick => {
+                        // An empty routing table just means no bootstrap peer is known yet
+                        let _ = self.swarm.behaviour_mut().kademlia.bootstrap();
+                    }
+                    EventType::QueryDeadline => {
+                        let behaviour = self.swarm.behaviour_mut();
+                        behaviour.send_hedges(Instant::now());
+                        for query in behaviour.queries.expire(Instant::now()) {
+                            behaviour.finish_query(query, true);
+                        }
+                    }
+                    EventType::Input(line) => {
+                        // A bad command is reported and the node keeps running
+                        if let Err(e) = self.execute(&line).await {
+                            report_command_error(e.to_string());
+                            self.failure = Some(e);
+                        }
+                    }
+                    EventType::Shutdown => {
+                        self.shutdown().await;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn dial_discovered_peers(swarm: &mut Swarm<NFTInfoBehaviour>) {
+    let pending = swarm
+        .behaviour_mut()
+        .pending_dials
+        .drain()
+        .collect::<Vec<_>>();
+    for peer in pending {
+        if !swarm.is_connected(&peer) && swarm.behaviour().access.is_allowed(&peer) {
+            if let Err(e) = swarm.dial(&peer) {
+                error!("error dialing {}: {:?}", peer, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A node that doesn't touch the network or the disk
+    async fn test_node() -> PeerCacheNode {
+        PeerCacheNode::new_isolated(NodeConfig {
+            ephemeral: true,
+            ..Default::default()
+        })
+        .await
+        .expect("can start a node")
+    }
+
+    fn subscribed(node: &PeerCacheNode, collection_name: &str) -> bool {
+        let behaviour = node.swarm.behaviour();
+        let topic = behaviour.namespace.collection_topic(collection_name).hash();
+        behaviour.gossipsub.topics().any(|t| t == &topic)
+    }
+
+    #[tokio::test]
+    async fn private_collections_can_be_made_public_again() {
+        let mut node = test_node().await;
+        node.execute("CREATE NFT apes|1|ape|alice").await.unwrap();
+        assert!(subscribed(&node, "apes"));
+
+        node.execute("PRIVATE COLLECTION apes").await.unwrap();
+        assert!(!subscribed(&node, "apes"));
+        assert!(shareable_nft_info(&node.store, &node.swarm.behaviour().local_only).is_empty());
+
+        node.execute("PUBLIC COLLECTION apes").await.unwrap();
+        assert!(subscribed(&node, "apes"));
+        assert_eq!(
+            shareable_nft_info(&node.store, &node.swarm.behaviour().local_only).len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn isolated_nodes_keep_a_local_store() {
+        let mut node = test_node().await;
+        node.execute("CREATE NFT apes|1|ape|alice").await.unwrap();
+        node.execute("UPDATE NFT apes|1|owner=bob").await.unwrap();
+        node.execute("SQL SELECT owner FROM nfts").await.unwrap();
+        assert_eq!(node.store().read()[0].owner, "bob");
+        // Nobody to ask
+        assert!(node.execute("GET NFT ALL").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn deleted_records_can_be_restored() {
+        let mut node = test_node().await;
+        node.execute("CREATE NFT apes|1|ape|alice").await.unwrap();
+        node.execute("DELETE NFT apes|1").await.unwrap();
+        assert!(node.store().read().is_empty());
+        node.execute("TRASH LIST").await.unwrap();
+
+        node.execute("TRASH RESTORE apes|1").await.unwrap();
+        assert_eq!(node.store().read()[0].owner, "alice");
+        assert!(node.execute("TRASH RESTORE apes|1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drafts_can_be_published_by_collection() {
+        let mut node = test_node().await;
+        node.execute("CREATE DRAFT apes|2|ape|alice").await.unwrap();
+        node.execute("CREATE DRAFT apes|1|ape|bob").await.unwrap();
+        node.execute("CREATE DRAFT cats|1|cat|carol").await.unwrap();
+
+        node.execute("PUBLISH COLLECTION apes").await.unwrap();
+        let drafts = node
+            .store()
+            .read()
+            .iter()
+            .filter(|r| r.draft)
+            .map(|r| r.collection_name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(drafts, vec!["cats"]);
+        assert!(node.execute("PUBLISH COLLECTION apes").await.is_err());
+        assert!(node.execute("PUBLISH COLLECTION ").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_commands_are_kept_for_the_exit_status() {
+        let mut node = test_node().await;
+        let (command_sender, command_rcv) = mpsc::unbounded_channel();
+        command_sender
+            .send("CREATE NFT apes|1|ape|alice".to_owned())
+            .unwrap();
+        command_sender.send("GET NFT ALL".to_owned()).unwrap();
+        drop(command_sender);
+        node.run(command_rcv).await;
+        assert!(node.take_failure().is_some());
+        assert!(node.take_failure().is_none());
+    }
+}
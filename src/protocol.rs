@@ -0,0 +1,99 @@
+// Direct request-response protocol for NFT queries. Requests and responses
+// travel over a stream to the peer that was asked only, instead of being
+// broadcast on the gossipsub topic for everyone to see.
+use crate::{ListRequest, ListResponse};
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::RequestResponseCodec;
+use std::io;
+
+// Upper bound for a single encoded request or response
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+pub const PROTOCOL_NAME: &str = "/peercache/nft-query/1.0.0";
+
+// Holds the protocol name, which is prefixed with the node's namespace
+#[derive(Debug, Clone)]
+pub struct NFTQueryProtocol(pub Vec<u8>);
+
+impl ProtocolName for NFTQueryProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Clone)]
+pub struct NFTQueryCodec;
+
+#[async_trait]
+impl RequestResponseCodec for NFTQueryCodec {
+    type Protocol = NFTQueryProtocol;
+    type Request = ListRequest;
+    type Response = ListResponse;
+
+    async fn read_request<T>(&mut self, _: &NFTQueryProtocol, io: &mut T) -> io::Result<ListRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &NFTQueryProtocol,
+        io: &mut T,
+    ) -> io::Result<ListResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &NFTQueryProtocol,
+        io: &mut T,
+        req: ListRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &NFTQueryProtocol,
+        io: &mut T,
+        res: ListResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &res).await
+    }
+}
+
+async fn read_json<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: serde::de::DeserializeOwned,
+{
+    let bytes = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+    if bytes.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_json<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: serde::Serialize,
+{
+    let bytes =
+        serde_json::to_vec(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_length_prefixed(io, bytes).await?;
+    io.close().await
+}
@@ -0,0 +1,384 @@
+// Bookkeeping for network queries that are still waiting on peer responses.
+// A query finishes once every peer it was sent to has answered (or failed),
+// or when its deadline passes with whatever arrived so far.
+use crate::config::TimeoutConfig;
+use crate::follow::Follow;
+use crate::{ListMode, NFTInfo};
+use libp2p::request_response::RequestId;
+use libp2p::PeerId;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+// Longest a query may wait, whatever its configured or requested timeout
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub struct PendingQuery {
+    // Shown to the user so interleaved results can be told apart
+    pub id: u64,
+    pub mode: ListMode,
+    pub started: Instant,
+    pub timeout: Duration,
+    pub expected: usize,
+    // Requests still waiting for an answer
+    pub outstanding: HashSet<RequestId>,
+    pub responders: HashSet<PeerId>,
+    // Records received so far, deduplicated by collection and item id
+    pub records: Vec<NFTInfo>,
+    // Peers that returned each record, in the same order as records
+    pub sources: Vec<Vec<PeerId>>,
+    // Item to look up in the DHT if no peer returns it
+    pub dht_item: Option<u32>,
+    // Sync query of a FOLLOW: the results are mirrored into the local store
+    pub mirror: Option<Follow>,
+    // A request timed out, so peers that hold records may not have answered
+    pub partial: bool,
+}
+
+impl PendingQuery {
+    pub fn deadline(&self) -> Instant {
+        // Timeouts are capped by start, but PendingQuery can be built elsewhere
+        self.started
+            .checked_add(self.timeout)
+            .unwrap_or_else(|| self.started + MAX_TIMEOUT)
+    }
+}
+
+pub enum ResponseStatus {
+    // No query is waiting for this response, it arrived late or was never asked for
+    Unsolicited,
+    Pending,
+    Complete(PendingQuery),
+}
+
+pub struct QueryTracker {
+    timeouts: TimeoutConfig,
+    pending: Vec<PendingQuery>,
+    next_id: u64,
+}
+
+impl QueryTracker {
+    pub fn new(timeouts: TimeoutConfig) -> Self {
+        QueryTracker {
+            timeouts,
+            pending: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    // Reserves the number of the next query, sent along with its requests
+    pub fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn start(
+        &mut self,
+        id: u64,
+        mode: ListMode,
+        timeout: Option<Duration>,
+        requests: Vec<RequestId>,
+        dht_item: Option<u32>,
+        mirror: Option<Follow>,
+    ) {
+        let timeout = timeout
+            .unwrap_or_else(|| self.timeouts.for_mode(&mode))
+            .min(MAX_TIMEOUT);
+        self.pending.push(PendingQuery {
+            id,
+            mode,
+            started: Instant::now(),
+            timeout,
+            expected: requests.len(),
+            outstanding: requests.into_iter().collect(),
+            responders: HashSet::new(),
+            records: Vec::new(),
+            sources: Vec::new(),
+            dht_item,
+            mirror,
+            partial: false,
+        });
+    }
+
+    pub fn query_id(&self, request_id: RequestId) -> Option<u64> {
+        self.position(request_id)
+            .map(|index| self.pending[index].id)
+    }
+
+    pub fn record_response(
+        &mut self,
+        request_id: RequestId,
+        peer: PeerId,
+        records: &[NFTInfo],
+    ) -> ResponseStatus {
+        let index = match self.position(request_id) {
+            Some(index) => index,
+            None => return ResponseStatus::Unsolicited,
+        };
+        let query = &mut self.pending[index];
+        query.responders.insert(peer);
+        for record in records {
+            let known = query.records.iter().position(|r| {
+                r.collection_name == record.collection_name && r.item_id == record.item_id
+            });
+            match known {
+                // A higher sequence number is a newer version of the same record
+                Some(index) if record.seq > query.records[index].seq => {
+                    query.records[index] = record.clone();
+                    query.sources[index] = vec![peer];
+                }
+                Some(index) => query.sources[index].push(peer),
+                None => {
+                    query.records.push(record.clone());
+                    query.sources.push(vec![peer]);
+                }
+            }
+        }
+        self.answered(index, request_id)
+    }
+
+    // The peer could not be reached or did not answer; stop waiting for it
+    pub fn record_failure(&mut self, request_id: RequestId) -> ResponseStatus {
+        match self.position(request_id) {
+            Some(index) => self.answered(index, request_id),
+            None => ResponseStatus::Unsolicited,
+        }
+    }
+
+    // Like a failure, but the results are only partial
+    pub fn record_timeout(&mut self, request_id: RequestId) -> ResponseStatus {
+        match self.position(request_id) {
+            Some(index) => {
+                self.pending[index].partial = true;
+                self.answered(index, request_id)
+            }
+            None => ResponseStatus::Unsolicited,
+        }
+    }
+
+    pub fn is_pending(&self, id: u64) -> bool {
+        self.pending.iter().any(|q| q.id == id)
+    }
+
+    // Adds a request sent later for a query, e.g. a hedge
+    pub fn add_request(&mut self, id: u64, request_id: RequestId) {
+        if let Some(query) = self.pending.iter_mut().find(|q| q.id == id) {
+            query.outstanding.insert(request_id);
+            query.expected += 1;
+        }
+    }
+
+    // Stops waiting for a request whose answer isn't needed any more, without
+    // finishing the query
+    pub fn cancel(&mut self, request_id: RequestId) {
+        if let Some(index) = self.position(request_id) {
+            self.pending[index].outstanding.remove(&request_id);
+        }
+    }
+
+    fn position(&self, request_id: RequestId) -> Option<usize> {
+        self.pending
+            .iter()
+            .position(|q| q.outstanding.contains(&request_id))
+    }
+
+    fn answered(&mut self, index: usize, request_id: RequestId) -> ResponseStatus {
+        let query = &mut self.pending[index];
+        query.outstanding.remove(&request_id);
+        if query.outstanding.is_empty() {
+            ResponseStatus::Complete(self.pending.remove(index))
+        } else {
+            ResponseStatus::Pending
+        }
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|q| q.deadline()).min()
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<PendingQuery> {
+        let (expired, pending) = self.pending.drain(..).partition(|q| q.deadline() <= now);
+        self.pending = pending;
+        expired
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::{NFTQueryCodec, NFTQueryProtocol, PROTOCOL_NAME};
+    use crate::ListRequest;
+    use libp2p::request_response::{ProtocolSupport, RequestResponse, RequestResponseConfig};
+
+    // Request ids can only be made by sending requests; they are queued until
+    // the behaviour is polled, which never happens here
+    pub fn request_ids(n: usize) -> Vec<RequestId> {
+        let mut behaviour = RequestResponse::new(
+            NFTQueryCodec,
+            std::iter::once((
+                NFTQueryProtocol(PROTOCOL_NAME.as_bytes().to_vec()),
+                ProtocolSupport::Full,
+            )),
+            RequestResponseConfig::default(),
+        );
+        (0..n)
+            .map(|_| {
+                let request = ListRequest {
+                    mode: ListMode::ALL,
+                    request_id: 0,
+                };
+                behaviour.send_request(&PeerId::random(), request)
+            })
+            .collect()
+    }
+
+    fn nft(item_id: u32, seq: Option<u64>) -> NFTInfo {
+        NFTInfo {
+            collection_name: "apes".to_owned(),
+            item_id,
+            seq,
+            ..Default::default()
+        }
+    }
+
+    fn tracker() -> QueryTracker {
+        QueryTracker::new(TimeoutConfig::default())
+    }
+
+    fn complete(status: ResponseStatus) -> PendingQuery {
+        match status {
+            ResponseStatus::Complete(query) => query,
+            ResponseStatus::Pending => panic!("query is still pending"),
+            ResponseStatus::Unsolicited => panic!("response was unsolicited"),
+        }
+    }
+
+    #[test]
+    fn numbers_queries() {
+        let mut queries = tracker();
+        assert_eq!(queries.next_id(), 1);
+        assert_eq!(queries.next_id(), 2);
+    }
+
+    #[test]
+    fn completes_once_every_peer_answered() {
+        let mut queries = tracker();
+        let ids = request_ids(2);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        queries.start(7, ListMode::ALL, None, ids.clone(), None, None);
+        assert_eq!(queries.query_id(ids[1]), Some(7));
+
+        let status = queries.record_response(ids[0], alice, &[nft(1, None)]);
+        assert!(matches!(status, ResponseStatus::Pending));
+        let query = complete(queries.record_response(ids[1], bob, &[nft(1, None), nft(2, None)]));
+        assert_eq!(query.id, 7);
+        assert_eq!(query.expected, 2);
+        assert_eq!(query.records, vec![nft(1, None), nft(2, None)]);
+        assert_eq!(query.sources, vec![vec![alice, bob], vec![bob]]);
+        assert_eq!(query.responders.len(), 2);
+        assert_eq!(queries.pending_count(), 0);
+
+        // Late answers no longer belong to a query
+        let status = queries.record_response(ids[0], alice, &[]);
+        assert!(matches!(status, ResponseStatus::Unsolicited));
+        assert_eq!(queries.query_id(ids[0]), None);
+    }
+
+    #[test]
+    fn keeps_the_newest_version_of_a_record() {
+        let mut queries = tracker();
+        let ids = request_ids(2);
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        queries.start(1, ListMode::ALL, None, ids.clone(), None, None);
+        queries.record_response(ids[0], alice, &[nft(1, Some(2))]);
+        let query = complete(queries.record_response(ids[1], bob, &[nft(1, Some(5))]));
+        assert_eq!(query.records, vec![nft(1, Some(5))]);
+        // Only the peer that returned the newer version is a source of it
+        assert_eq!(query.sources, vec![vec![bob]]);
+    }
+
+    #[test]
+    fn failed_requests_count_as_answered() {
+        let mut queries = tracker();
+        let ids = request_ids(2);
+        queries.start(1, ListMode::ALL, None, ids.clone(), None, None);
+        assert!(matches!(
+            queries.record_failure(ids[0]),
+            ResponseStatus::Pending
+        ));
+        let query = complete(queries.record_failure(ids[1]));
+        assert!(query.records.is_empty());
+        assert!(query.responders.is_empty());
+        assert!(matches!(
+            queries.record_failure(ids[1]),
+            ResponseStatus::Unsolicited
+        ));
+    }
+
+    #[test]
+    fn timed_out_requests_make_results_partial() {
+        let mut queries = tracker();
+        let ids = request_ids(2);
+        queries.start(1, ListMode::ALL, None, ids.clone(), None, None);
+        queries.record_response(ids[0], PeerId::random(), &[nft(1, None)]);
+        let query = complete(queries.record_timeout(ids[1]));
+        assert!(query.partial);
+        assert_eq!(query.records, vec![nft(1, None)]);
+
+        let ids = request_ids(1);
+        queries.start(2, ListMode::ALL, None, ids.clone(), None, None);
+        assert!(!complete(queries.record_failure(ids[0])).partial);
+    }
+
+    #[test]
+    fn expires_queries_past_their_deadline() {
+        let mut queries = tracker();
+        let ids = request_ids(2);
+        assert_eq!(queries.next_deadline(), None);
+        queries.start(
+            1,
+            ListMode::ALL,
+            Some(Duration::from_secs(1)),
+            vec![ids[0]],
+            None,
+            None,
+        );
+        let owner = ListMode::Owner("alice".to_owned());
+        queries.start(2, owner, None, vec![ids[1]], None, None);
+
+        let first = queries.next_deadline().expect("queries are pending");
+        assert!(queries.expire(first - Duration::from_millis(1)).is_empty());
+        let expired = queries.expire(first);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, 1);
+        assert_eq!(queries.pending_count(), 1);
+        assert_eq!(queries.query_id(ids[1]), Some(2));
+        // Without --timeout the timeout of the command class applies
+        let second = queries.next_deadline().expect("a query is pending");
+        let remaining = queries.expire(second);
+        assert_eq!(remaining[0].timeout, TimeoutConfig::default().list_owner);
+        assert_eq!(queries.next_deadline(), None);
+    }
+
+    #[test]
+    fn caps_timeouts() {
+        let mut queries = tracker();
+        let ids = request_ids(1);
+        queries.start(1, ListMode::ALL, Some(Duration::MAX), ids, None, None);
+        let deadline = queries.next_deadline().expect("a query is pending");
+        let query = queries.expire(deadline).remove(0);
+        assert_eq!(query.timeout, MAX_TIMEOUT);
+
+        // Built directly, a query's deadline still can't overflow
+        let query = PendingQuery {
+            timeout: Duration::MAX,
+            ..query
+        };
+        assert_eq!(query.deadline(), query.started + MAX_TIMEOUT);
+    }
+}
@@ -0,0 +1,113 @@
+// Answers inbound NFT queries on a fixed pool of worker tasks. Requests are
+// queued on a bounded channel and dequeued at a capped rate; the workers block
+// on the bounded response channel when the event loop falls behind, so the
+// queue fills up and further requests are refused instead of piling up memory.
+use crate::behaviour::{describe_query, PendingResponse};
+use crate::config::ResourceConfig;
+use crate::local_only::LocalOnly;
+use crate::slowlog::{self, SlowQuery};
+use crate::store::NftStore;
+use crate::{shareable_nft_info, ListMode, ListResponse};
+use libp2p::{request_response::ResponseChannel, PeerId};
+use log::error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{Interval, MissedTickBehavior};
+
+// An accepted request waiting for a worker
+pub struct Job {
+    pub channel: ResponseChannel<ListResponse>,
+    pub mode: ListMode,
+    pub peer: PeerId,
+    pub request_id: u64,
+}
+
+// The queue and the pace it is drained at, shared by the workers
+struct Queue {
+    jobs: mpsc::Receiver<Job>,
+    pace: Interval,
+}
+
+pub fn spawn_workers(
+    store: NftStore,
+    local_only: LocalOnly,
+    jobs: mpsc::Receiver<Job>,
+    responses: mpsc::Sender<PendingResponse>,
+    resources: &ResourceConfig,
+) {
+    let mut pace =
+        tokio::time::interval(Duration::from_secs(1) / resources.max_requests_per_second.max(1));
+    // Don't answer a burst of requests at once after an idle period
+    pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let queue = Arc::new(Mutex::new(Queue { jobs, pace }));
+    for _ in 0..resources.request_workers.max(1) {
+        let queue = queue.clone();
+        let store = store.clone();
+        let local_only = local_only.clone();
+        let responses = responses.clone();
+        tokio::spawn(async move {
+            loop {
+                let job = {
+                    let mut queue = queue.lock().await;
+                    queue.pace.tick().await;
+                    match queue.jobs.recv().await {
+                        Some(job) => job,
+                        None => break,
+                    }
+                };
+                let response = answer(&store, &local_only, job.mode, &job.peer, job.request_id);
+                if responses.send((job.channel, response)).await.is_err() {
+                    error!("error sending response via channel, the node has stopped");
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn answer(
+    store: &NftStore,
+    local_only: &LocalOnly,
+    mode: ListMode,
+    peer: &PeerId,
+    request_id: u64,
+) -> ListResponse {
+    let started = Instant::now();
+    let nft_info = shareable_nft_info(store, local_only);
+    let rows_scanned = nft_info.len();
+    let data = match &mode {
+        ListMode::ALL => nft_info,
+        ListMode::Collection(collection_name) => nft_info
+            .into_iter()
+            .filter(|r| r.collection_name.eq_ignore_ascii_case(collection_name))
+            .collect(),
+        // Only records sequenced after this number, in sequence order
+        ListMode::CollectionSince(collection_name, since) => {
+            let mut data = nft_info
+                .into_iter()
+                .filter(|r| r.collection_name.eq_ignore_ascii_case(collection_name))
+                .filter(|r| r.seq > Some(*since))
+                .collect::<Vec<_>>();
+            data.sort_by_key(|r| r.seq);
+            data
+        }
+        ListMode::Owner(owner) => nft_info
+            .into_iter()
+            .filter(|r| r.owner.eq_ignore_ascii_case(owner))
+            .collect(),
+    };
+    slowlog::record(SlowQuery {
+        source: peer.to_string(),
+        filter: describe_query(&mode),
+        rows_scanned,
+        peers: 0,
+        duration: started.elapsed(),
+    });
+    // Answer even without matching items so the requester isn't left waiting
+    ListResponse {
+        mode,
+        data,
+        request_id,
+    }
+}
@@ -0,0 +1,446 @@
+// Which hosts a collection query (GET NFT <collection>) is sent to when several
+// peers host the collection, and how fast and reliably each peer answered our
+// queries so far. By default every host is asked. A query sent to a single host
+// can be hedged: if it isn't answered in time, the next-best host is asked too
+// and whichever answers first wins. Hosts whose circuit breaker is open are
+// skipped.
+use crate::breaker::{Breaker, BreakerState};
+use crate::config::{SelectionConfig, SelectionPolicy};
+use crate::latency::PeerLatency;
+use crate::ListMode;
+use libp2p::request_response::RequestId;
+use libp2p::PeerId;
+use log::info;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+const SAMPLES: usize = 10;
+// Response times across all peers the hedging delay is taken from
+const HEDGE_SAMPLES: usize = 100;
+
+// A second request to send if the first isn't answered by then
+#[derive(Debug)]
+pub struct Hedge {
+    pub query_id: u64,
+    pub mode: ListMode,
+    pub first: RequestId,
+    pub backup: PeerId,
+    pub at: Instant,
+}
+
+// How a peer answered our queries
+#[derive(Debug, Default)]
+pub struct PeerQuality {
+    response_times: VecDeque<Duration>,
+    pub responses: u32,
+    pub failures: u32,
+    pub breaker: Breaker,
+}
+
+impl PeerQuality {
+    fn record(&mut self, response_time: Duration) {
+        if self.response_times.len() == SAMPLES {
+            self.response_times.pop_front();
+        }
+        self.response_times.push_back(response_time);
+        self.responses += 1;
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        let total = self.response_times.iter().sum::<Duration>();
+        (!self.response_times.is_empty()).then(|| total / self.response_times.len() as u32)
+    }
+
+    // Time to expect for an answer: the average response time (the ping round
+    // trip before the first response), up to twice as long the more often the
+    // peer failed
+    fn expected(&self, ping: Option<Duration>) -> Option<Duration> {
+        let base = self.average().or(ping)?;
+        let attempts = (self.responses + self.failures).max(1);
+        Some(base.mul_f64(1.0 + f64::from(self.failures) / f64::from(attempts)))
+    }
+}
+
+pub struct Selector {
+    config: SelectionConfig,
+    pub quality: HashMap<PeerId, PeerQuality>,
+    // Requests waiting for an answer, to time it
+    sent: HashMap<RequestId, (PeerId, Instant)>,
+    // Turn of the round-robin policy
+    turn: usize,
+    recent: VecDeque<Duration>,
+    hedges: Vec<Hedge>,
+    // Both requests of a sent hedge, with their rival and whether it is the hedge
+    races: HashMap<RequestId, (RequestId, bool)>,
+    // Requests that lost their race, whose answers are no longer needed
+    cancelled: HashSet<RequestId>,
+    pub hedged: u64,
+    // Hedges answered before the request they backed up
+    pub hedge_wins: u64,
+}
+
+impl Selector {
+    pub fn new(config: SelectionConfig) -> Self {
+        Selector {
+            config,
+            quality: HashMap::new(),
+            sent: HashMap::new(),
+            turn: 0,
+            recent: VecDeque::new(),
+            hedges: Vec::new(),
+            races: HashMap::new(),
+            cancelled: HashSet::new(),
+            hedged: 0,
+            hedge_wins: 0,
+        }
+    }
+
+    // Picks the hosts to ask according to the policy, and the next-best host
+    // to hedge with when hedging is on and a single host is asked
+    pub fn select(
+        &mut self,
+        hosts: Vec<PeerId>,
+        latency: &HashMap<PeerId, PeerLatency>,
+    ) -> (Vec<PeerId>, Option<PeerId>) {
+        let now = Instant::now();
+        let count = hosts.len();
+        let hosts = hosts
+            .into_iter()
+            .filter(|host| self.allows(host, now))
+            .collect::<Vec<_>>();
+        if hosts.is_empty() && count > 0 {
+            info!("All {} hosts have an open circuit breaker", count);
+        }
+        let peers = self.pick(hosts.clone(), latency);
+        if !self.config.hedge || peers.len() != 1 {
+            return (peers, None);
+        }
+        let mut others = hosts
+            .into_iter()
+            .filter(|host| !peers.contains(host))
+            .collect::<Vec<_>>();
+        self.rank(&mut others, latency);
+        (peers, others.first().copied())
+    }
+
+    fn pick(
+        &mut self,
+        mut peers: Vec<PeerId>,
+        latency: &HashMap<PeerId, PeerLatency>,
+    ) -> Vec<PeerId> {
+        match self.config.policy {
+            SelectionPolicy::All => (),
+            SelectionPolicy::Fastest => {
+                self.rank(&mut peers, latency);
+                peers.truncate(1);
+            }
+            SelectionPolicy::RoundRobin if !peers.is_empty() => {
+                peers.sort();
+                let peer = peers[self.turn % peers.len()];
+                self.turn = self.turn.wrapping_add(1);
+                peers = vec![peer];
+            }
+            SelectionPolicy::RoundRobin => (),
+            SelectionPolicy::RandomK => {
+                peers.shuffle(&mut rand::thread_rng());
+                peers.truncate(self.config.k.max(1));
+            }
+        }
+        peers
+    }
+
+    // Fastest first; peers we know nothing about go last
+    pub fn rank(&self, peers: &mut [PeerId], latency: &HashMap<PeerId, PeerLatency>) {
+        peers.sort_by_cached_key(|peer| {
+            let ping = latency.get(peer).and_then(PeerLatency::average);
+            let expected = match self.quality.get(peer) {
+                Some(quality) => quality.expected(ping),
+                None => ping,
+            };
+            (expected.is_none(), expected)
+        });
+    }
+
+    fn allows(&self, peer: &PeerId, now: Instant) -> bool {
+        self.quality.get(peer).map_or(true, |quality| {
+            quality.breaker.allows(now, self.config.breaker_cooldown)
+        })
+    }
+
+    pub fn breaker_state(&self, peer: &PeerId) -> BreakerState {
+        self.quality
+            .get(peer)
+            .map_or(BreakerState::Closed, |quality| {
+                quality
+                    .breaker
+                    .state(Instant::now(), self.config.breaker_cooldown)
+            })
+    }
+
+    pub fn sent(&mut self, request_id: RequestId, peer: PeerId) {
+        let now = Instant::now();
+        self.sent.insert(request_id, (peer, now));
+        let cooldown = self.config.breaker_cooldown;
+        self.quality
+            .entry(peer)
+            .or_default()
+            .breaker
+            .sent(now, cooldown);
+    }
+
+    pub fn answered(&mut self, request_id: RequestId) {
+        if let Some((peer, sent)) = self.sent.remove(&request_id) {
+            let response_time = sent.elapsed();
+            let quality = self.quality.entry(peer).or_default();
+            quality.record(response_time);
+            quality.breaker.success();
+            if self.recent.len() == HEDGE_SAMPLES {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(response_time);
+        }
+        // Answered in time, no need to hedge
+        self.hedges.retain(|hedge| hedge.first != request_id);
+    }
+
+    pub fn failed(&mut self, request_id: RequestId) {
+        if let Some((peer, _)) = self.sent.remove(&request_id) {
+            let quality = self.quality.entry(peer).or_default();
+            quality.failures += 1;
+            quality
+                .breaker
+                .failure(Instant::now(), self.config.breaker_failures);
+        }
+        // The query is over, or its rival is still running on its own
+        self.hedges.retain(|hedge| hedge.first != request_id);
+        if let Some((rival, _)) = self.races.remove(&request_id) {
+            self.races.remove(&rival);
+        }
+    }
+
+    // The hedge_percentile of recent response times; no hedging before there
+    // are enough of them
+    pub fn hedge_delay(&self) -> Option<Duration> {
+        if self.recent.len() < SAMPLES {
+            return None;
+        }
+        let mut recent = self.recent.iter().copied().collect::<Vec<_>>();
+        recent.sort();
+        let percentile = usize::from(self.config.hedge_percentile.min(100));
+        Some(recent[(recent.len() - 1) * percentile / 100])
+    }
+
+    pub fn hedge(&mut self, query_id: u64, mode: ListMode, first: RequestId, backup: PeerId) {
+        if let Some(delay) = self.hedge_delay() {
+            self.hedges.push(Hedge {
+                query_id,
+                mode,
+                first,
+                backup,
+                at: Instant::now() + delay,
+            });
+        }
+    }
+
+    pub fn next_hedge(&self) -> Option<Instant> {
+        self.hedges.iter().map(|hedge| hedge.at).min()
+    }
+
+    pub fn due_hedges(&mut self, now: Instant) -> Vec<Hedge> {
+        let (due, waiting) = self.hedges.drain(..).partition(|hedge| hedge.at <= now);
+        self.hedges = waiting;
+        due
+    }
+
+    pub fn hedge_sent(&mut self, first: RequestId, request_id: RequestId, backup: PeerId) {
+        self.sent(request_id, backup);
+        self.races.insert(first, (request_id, false));
+        self.races.insert(request_id, (first, true));
+        self.hedged += 1;
+    }
+
+    // Called when a request is answered; returns the rival request to stop waiting for
+    pub fn won(&mut self, request_id: RequestId) -> Option<RequestId> {
+        let (rival, is_hedge) = self.races.remove(&request_id)?;
+        self.races.remove(&rival);
+        self.cancelled.insert(rival);
+        if is_hedge {
+            self.hedge_wins += 1;
+        }
+        Some(rival)
+    }
+
+    // Whether the request lost its race; its answer (or failure) is then dropped
+    pub fn lost(&mut self, request_id: RequestId) -> bool {
+        self.cancelled.remove(&request_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::tests::request_ids;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn selector(policy: SelectionPolicy) -> Selector {
+        Selector::new(SelectionConfig {
+            policy,
+            ..Default::default()
+        })
+    }
+
+    fn pinged(rtts: &[(PeerId, u64)]) -> HashMap<PeerId, PeerLatency> {
+        rtts.iter()
+            .map(|(peer, rtt)| {
+                let mut latency = PeerLatency::default();
+                latency.record(ms(*rtt));
+                (*peer, latency)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn all_asks_every_host() {
+        let peers = vec![PeerId::random(), PeerId::random(), PeerId::random()];
+        let selected = selector(SelectionPolicy::All).select(peers.clone(), &HashMap::new());
+        assert_eq!(selected, peers);
+    }
+
+    #[test]
+    fn fastest_prefers_quick_and_reliable_hosts() {
+        let (slow, fast, unknown) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let latency = pinged(&[(slow, 80), (fast, 20)]);
+        let mut selector = selector(SelectionPolicy::Fastest);
+        assert_eq!(
+            selector.select(vec![unknown, slow, fast], &latency),
+            vec![fast]
+        );
+
+        // Response times measured on queries take over from pings, and failures count
+        selector.quality.insert(
+            fast,
+            PeerQuality {
+                response_times: VecDeque::from(vec![ms(50)]),
+                responses: 1,
+                failures: 1,
+                ..Default::default()
+            },
+        );
+        let mut ranked = vec![unknown, fast, slow];
+        selector.rank(&mut ranked, &latency);
+        assert_eq!(ranked, vec![fast, slow, unknown]);
+        selector.quality.get_mut(&fast).unwrap().failures = 3;
+        selector.rank(&mut ranked, &latency);
+        assert_eq!(ranked, vec![slow, fast, unknown]);
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut peers = vec![PeerId::random(), PeerId::random(), PeerId::random()];
+        let mut selector = selector(SelectionPolicy::RoundRobin);
+        let picked = (0..3)
+            .flat_map(|_| selector.select(peers.clone(), &HashMap::new()))
+            .collect::<Vec<_>>();
+        peers.sort();
+        assert_eq!(picked, peers);
+        assert!(selector.select(Vec::new(), &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn random_k_asks_k_hosts() {
+        let peers = vec![PeerId::random(), PeerId::random(), PeerId::random()];
+        let mut selector = selector(SelectionPolicy::RandomK);
+        let selected = selector.select(peers.clone(), &HashMap::new());
+        assert_eq!(selected.len(), 2);
+        assert!(selected.iter().all(|peer| peers.contains(peer)));
+        assert_eq!(
+            selector.select(peers[..1].to_vec(), &HashMap::new()).len(),
+            1
+        );
+    }
+
+    fn hedging() -> Selector {
+        let mut selector = Selector::new(SelectionConfig {
+            policy: SelectionPolicy::Fastest,
+            hedge: true,
+            ..Default::default()
+        });
+        selector.recent = (1..=SAMPLES as u64).map(|rtt| ms(rtt * 10)).collect();
+        selector
+    }
+
+    #[test]
+    fn hedges_with_the_next_best_host() {
+        let (slow, fast, slowest) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let latency = pinged(&[(slow, 80), (fast, 20), (slowest, 200)]);
+        let mut selector = hedging();
+        assert_eq!(
+            selector.select(vec![slowest, slow, fast], &latency),
+            (vec![fast], Some(slow))
+        );
+        // Only queries sent to a single host are hedged
+        selector.config.policy = SelectionPolicy::All;
+        assert_eq!(selector.select(vec![slow, fast], &latency).1, None);
+    }
+
+    #[test]
+    fn hedges_after_the_percentile_delay() {
+        let mut selector = hedging();
+        assert_eq!(selector.hedge_delay(), Some(ms(90)));
+        selector.config.hedge_percentile = 50;
+        assert_eq!(selector.hedge_delay(), Some(ms(50)));
+        selector.recent.truncate(SAMPLES - 1);
+        assert_eq!(selector.hedge_delay(), None);
+    }
+
+    #[test]
+    fn the_first_answer_wins_the_race() {
+        let ids = request_ids(4);
+        let backup = PeerId::random();
+        let mut selector = hedging();
+
+        selector.hedge(1, ListMode::ALL, ids[0], backup);
+        let now = Instant::now();
+        assert!(selector.due_hedges(now).is_empty());
+        let hedge = selector.due_hedges(now + Duration::from_secs(1)).remove(0);
+        assert_eq!((hedge.first, hedge.backup), (ids[0], backup));
+        assert_eq!(selector.next_hedge(), None);
+
+        selector.hedge_sent(ids[0], ids[1], backup);
+        assert_eq!(selector.hedged, 1);
+        assert_eq!(selector.won(ids[1]), Some(ids[0]));
+        assert_eq!(selector.hedge_wins, 1);
+        assert!(selector.lost(ids[0]));
+        assert!(!selector.lost(ids[0]));
+
+        // An answer in time makes the hedge unnecessary
+        selector.hedge(2, ListMode::ALL, ids[2], backup);
+        selector.sent(ids[2], PeerId::random());
+        selector.answered(ids[2]);
+        assert_eq!(selector.next_hedge(), None);
+        assert_eq!(selector.won(ids[2]), None);
+        assert_eq!(selector.hedge_wins, 1);
+    }
+
+    #[test]
+    fn skips_hosts_with_an_open_breaker() {
+        let ids = request_ids(3);
+        let (flaky, steady) = (PeerId::random(), PeerId::random());
+        let mut selector = selector(SelectionPolicy::All);
+        for request_id in ids {
+            selector.sent(request_id, flaky);
+            selector.failed(request_id);
+        }
+        assert_eq!(selector.breaker_state(&flaky), BreakerState::Open);
+        assert_eq!(selector.breaker_state(&steady), BreakerState::Closed);
+        assert_eq!(
+            selector.select(vec![flaky, steady], &HashMap::new()).0,
+            vec![steady]
+        );
+        assert!(selector.select(vec![flaky], &HashMap::new()).0.is_empty());
+    }
+}
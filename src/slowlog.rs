@@ -0,0 +1,55 @@
+// Recent queries that took longer than a threshold: local SQL, queries served
+// to other peers and network queries we sent. Browsable with SLOWLOG.
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Only the most recent entries are kept
+const CAPACITY: usize = 128;
+
+static ENTRIES: Lazy<Mutex<VecDeque<SlowQuery>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+static THRESHOLD_MS: AtomicU64 = AtomicU64::new(100);
+
+#[derive(Debug, Clone)]
+pub struct SlowQuery {
+    // "local", "network" or the peer id a query was served to
+    pub source: String,
+    pub filter: String,
+    pub rows_scanned: usize,
+    pub peers: usize,
+    pub duration: Duration,
+}
+
+pub fn threshold() -> Duration {
+    Duration::from_millis(THRESHOLD_MS.load(Ordering::Relaxed))
+}
+
+pub fn set_threshold(threshold: Duration) {
+    THRESHOLD_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn record(query: SlowQuery) {
+    if query.duration < threshold() {
+        return;
+    }
+    let mut entries = ENTRIES.lock().expect("can lock slow log");
+    if entries.len() == CAPACITY {
+        entries.pop_front();
+    }
+    entries.push_back(query);
+}
+
+pub fn entries() -> Vec<SlowQuery> {
+    ENTRIES
+        .lock()
+        .expect("can lock slow log")
+        .iter()
+        .cloned()
+        .collect()
+}
+
+pub fn reset() {
+    ENTRIES.lock().expect("can lock slow log").clear();
+}
@@ -0,0 +1,467 @@
+// A small SQL subset evaluated against the local NFT store, e.g.
+// SELECT owner, COUNT(*) FROM nfts WHERE collection_name = 'apes' GROUP BY owner
+use crate::{NFTInfo, Result};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Symbol(char),
+    NotEq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Column {
+    CollectionName,
+    ItemId,
+    Description,
+    Owner,
+}
+
+const ALL_COLUMNS: [Column; 4] = [
+    Column::CollectionName,
+    Column::ItemId,
+    Column::Description,
+    Column::Owner,
+];
+
+impl Column {
+    fn parse(name: &str) -> Result<Column> {
+        match name.to_ascii_lowercase().as_str() {
+            "collection_name" => Ok(Column::CollectionName),
+            "item_id" => Ok(Column::ItemId),
+            "description" => Ok(Column::Description),
+            "owner" => Ok(Column::Owner),
+            _ => Err(format!("unknown column '{}'", name).into()),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Column::CollectionName => "collection_name",
+            Column::ItemId => "item_id",
+            Column::Description => "description",
+            Column::Owner => "owner",
+        }
+    }
+
+    fn value(&self, nft: &NFTInfo) -> String {
+        match self {
+            Column::CollectionName => nft.collection_name.clone(),
+            Column::ItemId => nft.item_id.to_string(),
+            Column::Description => nft.description.clone(),
+            Column::Owner => nft.owner.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SelectItem {
+    All,
+    Column(Column),
+    Count,
+}
+
+#[derive(Debug)]
+struct Filter {
+    column: Column,
+    value: String,
+    negated: bool,
+}
+
+impl Filter {
+    fn matches(&self, nft: &NFTInfo) -> bool {
+        (self.column.value(nft) == self.value) != self.negated
+    }
+}
+
+#[derive(Debug)]
+struct Query {
+    items: Vec<SelectItem>,
+    filters: Vec<Filter>,
+    group_by: Option<Column>,
+    limit: Option<usize>,
+}
+
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+pub fn execute(sql: &str, records: &[NFTInfo]) -> Result<ResultSet> {
+    let query = Parser::new(tokenize(sql)?).parse_query()?;
+    let matching = records
+        .iter()
+        .filter(|r| query.filters.iter().all(|f| f.matches(r)))
+        .collect::<Vec<_>>();
+
+    let aggregate =
+        query.group_by.is_some() || query.items.iter().any(|i| matches!(i, SelectItem::Count));
+    let mut result = if aggregate {
+        aggregate_rows(&query, &matching)?
+    } else {
+        project_rows(&query, &matching)
+    };
+    if let Some(limit) = query.limit {
+        result.rows.truncate(limit);
+    }
+    Ok(result)
+}
+
+fn project_rows(query: &Query, records: &[&NFTInfo]) -> ResultSet {
+    let columns = query
+        .items
+        .iter()
+        .flat_map(|item| match item {
+            SelectItem::All => ALL_COLUMNS.to_vec(),
+            SelectItem::Column(c) => vec![*c],
+            SelectItem::Count => vec![],
+        })
+        .collect::<Vec<_>>();
+    ResultSet {
+        columns: columns.iter().map(|c| c.name().to_owned()).collect(),
+        rows: records
+            .iter()
+            .map(|r| columns.iter().map(|c| c.value(r)).collect())
+            .collect(),
+    }
+}
+
+fn aggregate_rows(query: &Query, records: &[&NFTInfo]) -> Result<ResultSet> {
+    let mut groups: BTreeMap<Option<String>, usize> = BTreeMap::new();
+    for r in records {
+        *groups
+            .entry(query.group_by.map(|c| c.value(r)))
+            .or_insert(0) += 1;
+    }
+    // Without GROUP BY, COUNT(*) over an empty selection still yields a row
+    if query.group_by.is_none() && groups.is_empty() {
+        groups.insert(None, 0);
+    }
+
+    let mut columns = Vec::new();
+    for item in &query.items {
+        match item {
+            SelectItem::Count => columns.push("COUNT(*)".to_owned()),
+            SelectItem::Column(c) if Some(*c) == query.group_by => {
+                columns.push(c.name().to_owned())
+            }
+            SelectItem::Column(c) => {
+                return Err(format!("column '{}' must appear in GROUP BY", c.name()).into())
+            }
+            SelectItem::All => return Err("'*' cannot be combined with aggregates".into()),
+        }
+    }
+
+    let rows = groups
+        .into_iter()
+        .map(|(key, count)| {
+            query
+                .items
+                .iter()
+                .map(|item| match item {
+                    SelectItem::Count => count.to_string(),
+                    _ => key.clone().unwrap_or_default(),
+                })
+                .collect()
+        })
+        .collect();
+    Ok(ResultSet { columns, rows })
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("unterminated string literal".into()),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            '!' => {
+                chars.next();
+                if chars.next() != Some('=') {
+                    return Err("expected '!='".into());
+                }
+                tokens.push(Token::NotEq);
+            }
+            '(' | ')' | ',' | '*' | '=' | ';' => {
+                chars.next();
+                tokens.push(Token::Symbol(c));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            c => return Err(format!("unexpected character '{}'", c).into()),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn parse_query(&mut self) -> Result<Query> {
+        self.expect_keyword("SELECT")?;
+        let mut items = vec![self.select_item()?];
+        while self.eat_symbol(',') {
+            items.push(self.select_item()?);
+        }
+
+        self.expect_keyword("FROM")?;
+        let table = self.word()?;
+        if !table.eq_ignore_ascii_case("nfts") {
+            return Err(format!("unknown table '{}', only 'nfts' exists", table).into());
+        }
+
+        let mut filters = Vec::new();
+        if self.eat_keyword("WHERE") {
+            filters.push(self.filter()?);
+            while self.eat_keyword("AND") {
+                filters.push(self.filter()?);
+            }
+        }
+
+        let mut group_by = None;
+        if self.eat_keyword("GROUP") {
+            self.expect_keyword("BY")?;
+            group_by = Some(Column::parse(&self.word()?)?);
+        }
+
+        let mut limit = None;
+        if self.eat_keyword("LIMIT") {
+            let n = self.word()?;
+            limit = Some(
+                n.parse::<usize>()
+                    .map_err(|_| format!("invalid LIMIT '{}'", n))?,
+            );
+        }
+
+        self.eat_symbol(';');
+        if let Some(token) = self.tokens.get(self.pos) {
+            return Err(format!("unexpected trailing input at {:?}", token).into());
+        }
+        Ok(Query {
+            items,
+            filters,
+            group_by,
+            limit,
+        })
+    }
+
+    fn select_item(&mut self) -> Result<SelectItem> {
+        if self.eat_symbol('*') {
+            return Ok(SelectItem::All);
+        }
+        let word = self.word()?;
+        if word.eq_ignore_ascii_case("COUNT") {
+            self.expect_symbol('(')?;
+            self.expect_symbol('*')?;
+            self.expect_symbol(')')?;
+            return Ok(SelectItem::Count);
+        }
+        Ok(SelectItem::Column(Column::parse(&word)?))
+    }
+
+    fn filter(&mut self) -> Result<Filter> {
+        let column = Column::parse(&self.word()?)?;
+        let negated = match self.tokens.get(self.pos) {
+            Some(Token::Symbol('=')) => false,
+            Some(Token::NotEq) => true,
+            other => return Err(format!("expected '=' or '!=', found {:?}", other).into()),
+        };
+        self.pos += 1;
+        let value = match self.tokens.get(self.pos) {
+            Some(Token::Str(s)) | Some(Token::Word(s)) => s.clone(),
+            other => return Err(format!("expected a value, found {:?}", other).into()),
+        };
+        self.pos += 1;
+        Ok(Filter {
+            column,
+            value,
+            negated,
+        })
+    }
+
+    fn word(&mut self) -> Result<String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) => {
+                self.pos += 1;
+                Ok(w.clone())
+            }
+            other => Err(format!("expected identifier, found {:?}", other).into()),
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(format!("expected {}", keyword).into())
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: char) -> bool {
+        if self.tokens.get(self.pos) == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<()> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", symbol).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nft(collection_name: &str, item_id: u32, owner: &str) -> NFTInfo {
+        NFTInfo {
+            collection_name: collection_name.to_owned(),
+            item_id,
+            owner: owner.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn records() -> Vec<NFTInfo> {
+        vec![
+            nft("apes", 1, "alice"),
+            nft("apes", 2, "bob"),
+            nft("apes", 3, "alice"),
+            nft("punks", 1, "carol"),
+        ]
+    }
+
+    fn error(sql: &str) -> String {
+        match execute(sql, &records()) {
+            Ok(_) => panic!("'{}' should not parse", sql),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn selects_and_filters() {
+        let result = execute(
+            "SELECT item_id, owner FROM nfts WHERE collection_name = 'apes' AND owner != bob",
+            &records(),
+        )
+        .unwrap();
+        assert_eq!(result.columns, vec!["item_id", "owner"]);
+        assert_eq!(result.rows, vec![vec!["1", "alice"], vec!["3", "alice"]]);
+    }
+
+    #[test]
+    fn groups_and_counts() {
+        let result = execute(
+            "select owner, count(*) from NFTS group by owner;",
+            &records(),
+        )
+        .unwrap();
+        assert_eq!(result.columns, vec!["owner", "COUNT(*)"]);
+        assert_eq!(
+            result.rows,
+            vec![vec!["alice", "2"], vec!["bob", "1"], vec!["carol", "1"]]
+        );
+    }
+
+    #[test]
+    fn count_over_an_empty_set() {
+        let result = execute("SELECT COUNT(*) FROM nfts WHERE owner = 'dave'", &records()).unwrap();
+        assert_eq!(result.rows, vec![vec!["0"]]);
+
+        let result = execute(
+            "SELECT owner, COUNT(*) FROM nfts WHERE owner = 'dave' GROUP BY owner",
+            &records(),
+        )
+        .unwrap();
+        assert!(result.rows.is_empty());
+
+        let result = execute("SELECT COUNT(*) FROM nfts", &[]).unwrap();
+        assert_eq!(result.rows, vec![vec!["0"]]);
+    }
+
+    #[test]
+    fn limits_rows() {
+        let result = execute("SELECT * FROM nfts LIMIT 2", &records()).unwrap();
+        assert_eq!(result.columns.len(), ALL_COLUMNS.len());
+        assert_eq!(result.rows.len(), 2);
+
+        let result = execute(
+            "SELECT owner, COUNT(*) FROM nfts GROUP BY owner LIMIT 1",
+            &records(),
+        )
+        .unwrap();
+        assert_eq!(result.rows, vec![vec!["alice", "2"]]);
+
+        let result = execute("SELECT owner FROM nfts LIMIT 0", &records()).unwrap();
+        assert!(result.rows.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        assert!(error("SELECT color FROM nfts").contains("unknown column 'color'"));
+        assert!(error("SELECT * FROM users").contains("unknown table 'users'"));
+        assert!(error("SELECT * FROM nfts WHERE owner = 'alice").contains("unterminated"));
+        assert!(error("SELECT * FROM nfts WHERE owner < 'a'").contains("unexpected character"));
+        assert!(error("SELECT * FROM nfts WHERE owner 'a'").contains("expected '=' or '!='"));
+        assert!(error("SELECT * FROM nfts LIMIT ten").contains("invalid LIMIT 'ten'"));
+        assert!(error("SELECT * FROM nfts LIMIT 1 2").contains("trailing input"));
+        assert!(error("SELECT COUNT(owner) FROM nfts").contains("expected '*'"));
+        assert!(error("* FROM nfts").contains("expected SELECT"));
+        assert!(error("SELECT owner FROM").contains("expected identifier"));
+    }
+
+    #[test]
+    fn rejects_columns_outside_group_by() {
+        assert!(error("SELECT owner, COUNT(*) FROM nfts").contains("must appear in GROUP BY"));
+        assert!(error("SELECT item_id, COUNT(*) FROM nfts GROUP BY owner")
+            .contains("must appear in GROUP BY"));
+        assert!(error("SELECT *, COUNT(*) FROM nfts").contains("cannot be combined"));
+    }
+}
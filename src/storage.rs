@@ -0,0 +1,222 @@
+// On-disk copy of the local NFT store: a JSON file in the data directory that
+// is read back on startup and rewritten after every change to the store.
+// On startup the file is checked and repaired where possible: a file that
+// isn't a JSON list any more is moved aside and the node starts empty, records
+// that don't parse and older duplicates of a record are moved to a rejects
+// file, and the repaired store is saved (the original is kept as a backup).
+use crate::{NFTInfo, Result};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const STORE_FILE: &str = "nft_store.json";
+const CORRUPT_FILE: &str = "nft_store.json.corrupt";
+const BACKUP_FILE: &str = "nft_store.json.bak";
+const REJECTED_FILE: &str = "nft_store.rejected.json";
+
+// A stored entry that couldn't be kept, and why
+#[derive(Debug, Serialize)]
+struct Rejected {
+    reason: String,
+    entry: Value,
+}
+
+// Returns the saved records and the path the store is saved to from now on
+pub fn init(data_dir: &Path) -> Result<(Vec<NFTInfo>, PathBuf)> {
+    fs::create_dir_all(data_dir)?;
+    let path = data_dir.join(STORE_FILE);
+    // Left behind by a write that was interrupted before it replaced the store
+    let tmp = path.with_extension("json.tmp");
+    if tmp.exists() {
+        warn!("removing {} from an interrupted save", tmp.display());
+        fs::remove_file(&tmp)?;
+    }
+    if !path.exists() {
+        return Ok((Vec::new(), path));
+    }
+    let entries = match serde_json::from_slice::<Vec<Value>>(&fs::read(&path)?) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let corrupt = data_dir.join(CORRUPT_FILE);
+            fs::rename(&path, &corrupt)?;
+            error!(
+                "{} is unreadable ({}), moved it to {} and starting with an empty store",
+                path.display(),
+                e,
+                corrupt.display()
+            );
+            return Ok((Vec::new(), path));
+        }
+    };
+    let (records, rejected) = check(entries);
+    if !rejected.is_empty() {
+        for r in &rejected {
+            error!("rejected a stored record: {}", r.reason);
+        }
+        let rejected_path = data_dir.join(REJECTED_FILE);
+        append_rejected(&rejected_path, rejected)?;
+        fs::copy(&path, data_dir.join(BACKUP_FILE))?;
+        write(&path, &records)?;
+        info!(
+            "Repaired {}, rejected records are in {}",
+            path.display(),
+            rejected_path.display()
+        );
+    }
+    warn_duplicate_seqs(&records);
+    Ok((records, path))
+}
+
+// Keeps the entries that parse, and of several copies of a record the one with
+// the highest sequence number (the last one when they are equal)
+fn check(entries: Vec<Value>) -> (Vec<NFTInfo>, Vec<Rejected>) {
+    let mut records: Vec<NFTInfo> = Vec::new();
+    let mut rejected = Vec::new();
+    let mut index = HashMap::new();
+    for entry in entries {
+        let record = match serde_json::from_value::<NFTInfo>(entry.clone()) {
+            Ok(record) => record,
+            Err(e) => {
+                rejected.push(Rejected {
+                    reason: format!("invalid record: {}", e),
+                    entry,
+                });
+                continue;
+            }
+        };
+        let id = (record.collection_name.clone(), record.item_id);
+        let reason = format!("older copy of {}|{}", id.0, id.1);
+        match index.get(&id) {
+            Some(&i) if records[i].seq > record.seq => rejected.push(Rejected { reason, entry }),
+            Some(&i) => {
+                let older = std::mem::replace(&mut records[i], record);
+                rejected.push(Rejected {
+                    reason,
+                    entry: serde_json::to_value(older).expect("can jsonify a record"),
+                });
+            }
+            None => {
+                index.insert(id, records.len());
+                records.push(record);
+            }
+        }
+    }
+    (records, rejected)
+}
+
+// Followers asking for changes since a sequence number would miss one of two
+// records sharing it; only the creator can fix this, by changing one of them
+fn warn_duplicate_seqs(records: &[NFTInfo]) {
+    let mut seen = BTreeMap::new();
+    for record in records {
+        if let (Some(seq), Some(creator)) = (record.seq, &record.creator) {
+            let key = (record.collection_name.as_str(), creator.as_str(), seq);
+            if let Some(item_id) = seen.insert(key, record.item_id) {
+                warn!(
+                    "{}|{} and {}|{} share sequence number {}",
+                    record.collection_name, item_id, record.collection_name, record.item_id, seq
+                );
+            }
+        }
+    }
+}
+
+fn append_rejected(path: &Path, rejected: Vec<Rejected>) -> Result<()> {
+    let mut all = match path.exists() {
+        true => serde_json::from_slice::<Vec<Value>>(&fs::read(path)?).unwrap_or_default(),
+        false => Vec::new(),
+    };
+    for r in rejected {
+        all.push(serde_json::to_value(r)?);
+    }
+    fs::write(path, serde_json::to_vec_pretty(&all)?)?;
+    Ok(())
+}
+
+pub fn flush(path: &Path, records: &[NFTInfo]) {
+    if let Err(e) = write(path, records) {
+        error!("error saving NFT store to {}: {}", path.display(), e);
+    }
+}
+
+fn write(path: &Path, records: &[NFTInfo]) -> Result<()> {
+    // Write to a temporary file first so a crash never leaves a truncated store
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(records)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("peercache-storage-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(item_id: u32, owner: &str, seq: Option<u64>) -> Value {
+        json!({
+            "collection_name": "apes",
+            "item_id": item_id,
+            "description": "ape",
+            "owner": owner,
+            "seq": seq,
+        })
+    }
+
+    #[test]
+    fn keeps_the_newest_copy_of_a_record() {
+        let entries = vec![
+            entry(1, "alice", Some(2)),
+            entry(1, "bob", Some(1)),
+            entry(2, "alice", None),
+            entry(2, "carol", None),
+            json!({"collection_name": "apes"}),
+        ];
+        let (records, rejected) = check(entries);
+        let owners = records.iter().map(|r| r.owner.as_str()).collect::<Vec<_>>();
+        assert_eq!(owners, vec!["alice", "carol"]);
+        assert_eq!(rejected.len(), 3);
+        assert!(rejected[2].reason.starts_with("invalid record"));
+    }
+
+    #[test]
+    fn repairs_the_store_file() {
+        let dir = temp_dir();
+        let entries = json!([entry(1, "alice", None), entry(1, "bob", None), 42]);
+        fs::write(dir.join(STORE_FILE), entries.to_string()).unwrap();
+
+        let (records, _) = init(&dir).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].owner, "bob");
+        let rejected: Vec<Value> =
+            serde_json::from_slice(&fs::read(dir.join(REJECTED_FILE)).unwrap()).unwrap();
+        assert_eq!(rejected.len(), 2);
+        assert!(dir.join(BACKUP_FILE).exists());
+        // The repaired store loads cleanly
+        let (records, _) = init(&dir).unwrap();
+        assert_eq!(records.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn moves_unreadable_stores_aside() {
+        let dir = temp_dir();
+        fs::write(dir.join(STORE_FILE), "[{\"collection_name\": \"ap").unwrap();
+        fs::write(dir.join("nft_store.json.tmp"), "[").unwrap();
+
+        let (records, _) = init(&dir).unwrap();
+        assert!(records.is_empty());
+        assert!(dir.join(CORRUPT_FILE).exists());
+        assert!(!dir.join(STORE_FILE).exists());
+        assert!(!dir.join("nft_store.json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
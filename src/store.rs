@@ -0,0 +1,190 @@
+// The local NFT store, shared between the main loop and the spawned responder tasks
+use crate::{next_seq, set_seq, storage, NFTInfo, Result};
+use libp2p::PeerId;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[derive(Debug, Clone, Default)]
+pub struct NftStore {
+    records: Arc<RwLock<Vec<NFTInfo>>>,
+    // File the store is saved to; None keeps it in memory only
+    path: Option<PathBuf>,
+}
+
+impl NftStore {
+    pub fn new(records: Vec<NFTInfo>) -> Self {
+        NftStore {
+            records: Arc::new(RwLock::new(records)),
+            path: None,
+        }
+    }
+
+    pub fn persistent(records: Vec<NFTInfo>, path: PathBuf) -> Self {
+        NftStore {
+            records: Arc::new(RwLock::new(records)),
+            path: Some(path),
+        }
+    }
+
+    // Saves the records, passed in since callers still hold the write guard
+    pub fn flush(&self, records: &[NFTInfo]) {
+        if let Some(path) = &self.path {
+            storage::flush(path, records);
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Vec<NFTInfo>> {
+        self.records.read().expect("can read NFT store")
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, Vec<NFTInfo>> {
+        self.records.write().expect("can write NFT store")
+    }
+
+    // Sets the description or owner of a record and saves the store
+    pub fn update(
+        &self,
+        local: &PeerId,
+        collection_name: &str,
+        item_id: u32,
+        field: &str,
+        value: &str,
+    ) -> Result<NFTInfo> {
+        let mut records = self.write();
+        let seq = next_seq(&records, collection_name, local);
+        let record = records
+            .iter_mut()
+            .find(|r| r.collection_name == collection_name && r.item_id == item_id)
+            .ok_or_else(|| format!("no NFT {}|{}", collection_name, item_id))?;
+        if record.burn.is_some() {
+            return Err(format!(
+                "{}|{} was burned and cannot be changed",
+                collection_name, item_id
+            )
+            .into());
+        }
+        match field {
+            "description" => record.description = value.to_owned(),
+            "owner" if !value.is_empty() => record.owner = value.to_owned(),
+            "owner" => return Err("owner cannot be empty".into()),
+            _ => return Err(format!("cannot update '{}', use description or owner", field).into()),
+        }
+        if !record.draft {
+            set_seq(record, seq, local);
+        }
+        let updated = record.clone();
+        self.flush(&records);
+        Ok(updated)
+    }
+
+    // Removes a record and saves the store. Burned records are kept so their id
+    // can never be reused.
+    pub fn delete(&self, local: &PeerId, collection_name: &str, item_id: u32) -> Result<NFTInfo> {
+        let mut records = self.write();
+        let index = records
+            .iter()
+            .position(|r| r.collection_name == collection_name && r.item_id == item_id)
+            .ok_or_else(|| format!("no NFT {}|{}", collection_name, item_id))?;
+        if records[index].burn.is_some() {
+            return Err(format!(
+                "{}|{} was burned and cannot be deleted",
+                collection_name, item_id
+            )
+            .into());
+        }
+        // Followers could never learn about the removal
+        if records[index].seq.is_some() && next_seq(&records, collection_name, local).is_some() {
+            return Err(format!(
+                "{} is a sequenced collection, burn {}|{} instead",
+                collection_name, collection_name, item_id
+            )
+            .into());
+        }
+        let deleted = records.remove(index);
+        self.flush(&records);
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BurnStatement;
+    use libp2p::identity::Keypair;
+
+    fn nft(item_id: u32) -> NFTInfo {
+        NFTInfo {
+            collection_name: "apes".to_owned(),
+            item_id,
+            description: "ape".to_owned(),
+            owner: "alice".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn updates_description_and_owner() {
+        let local = PeerId::random();
+        let store = NftStore::new(vec![nft(1)]);
+        let updated = store
+            .update(&local, "apes", 1, "description", "blue ape")
+            .unwrap();
+        assert_eq!(updated.description, "blue ape");
+        store.update(&local, "apes", 1, "owner", "bob").unwrap();
+        assert_eq!(store.read()[0].owner, "bob");
+
+        assert!(store.update(&local, "apes", 1, "owner", "").is_err());
+        assert!(store.update(&local, "apes", 1, "item_id", "2").is_err());
+        assert!(store.update(&local, "apes", 2, "owner", "bob").is_err());
+        // Unsequenced collections stay unsequenced
+        assert_eq!(store.read()[0].seq, None);
+    }
+
+    #[test]
+    fn updates_move_sequenced_records_to_the_next_number() {
+        let local = PeerId::random();
+        let sequenced = |item_id, seq| NFTInfo {
+            seq: Some(seq),
+            creator: Some(local.to_string()),
+            ..nft(item_id)
+        };
+        let store = NftStore::new(vec![sequenced(1, 1), sequenced(2, 2)]);
+        let updated = store.update(&local, "apes", 1, "owner", "bob").unwrap();
+        assert_eq!(updated.seq, Some(3));
+        // Records of a sequenced collection can only be burned
+        assert!(store.delete(&local, "apes", 2).is_err());
+    }
+
+    #[test]
+    fn keeps_burned_records() {
+        let keys = Keypair::generate_ed25519();
+        let local = PeerId::from(keys.public());
+        let burned = NFTInfo {
+            burn: Some(BurnStatement::sign(&keys, "apes", 1).unwrap()),
+            ..nft(1)
+        };
+        let store = NftStore::new(vec![burned, nft(2)]);
+        assert!(store.update(&local, "apes", 1, "owner", "bob").is_err());
+        assert!(store.delete(&local, "apes", 1).is_err());
+
+        assert_eq!(store.delete(&local, "apes", 2).unwrap(), nft(2));
+        assert!(store.delete(&local, "apes", 2).is_err());
+        assert_eq!(store.read().len(), 1);
+    }
+
+    #[test]
+    fn saves_changes() {
+        let dir = std::env::temp_dir().join(format!("peercache-store-{}", rand::random::<u64>()));
+        let (records, path) = storage::init(&dir).unwrap();
+        let store = NftStore::persistent(records, path);
+        store.write().push(nft(1));
+        store
+            .update(&PeerId::random(), "apes", 1, "owner", "bob")
+            .unwrap();
+
+        let (records, _) = storage::init(&dir).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].owner, "bob");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+// Peer graph assembled from our own view plus the topology announcements
+// gossiped by other peers, rendered for graphviz (dot) or web visualizers (json).
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TopologyAnnouncement {
+    pub peer: String,
+    pub neighbours: Vec<String>,
+    pub collections: Vec<String>,
+    // Average ping round-trip time to each neighbour, in milliseconds.
+    // Peers running older versions leave it and the roles out.
+    #[serde(default)]
+    pub latency_ms: BTreeMap<String, f64>,
+    // What the peer does besides holding collections, e.g. "dht-records"
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Node {
+    id: String,
+    local: bool,
+    collections: Vec<String>,
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Edge {
+    from: String,
+    to: String,
+    // Reported by either end, averaged when both did
+    latency_ms: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+fn build_graph(local: &str, views: &[TopologyAnnouncement]) -> Graph {
+    let mut nodes: BTreeMap<&str, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    let mut edges: BTreeMap<(String, String), Vec<f64>> = BTreeMap::new();
+    for view in views {
+        nodes.insert(
+            view.peer.as_str(),
+            (view.collections.clone(), view.roles.clone()),
+        );
+        for neighbour in &view.neighbours {
+            nodes.entry(neighbour.as_str()).or_default();
+            // Connections are undirected, so keep each pair only once
+            let (a, b) = if view.peer.as_str() < neighbour.as_str() {
+                (&view.peer, neighbour)
+            } else {
+                (neighbour, &view.peer)
+            };
+            let latencies = edges.entry((a.clone(), b.clone())).or_default();
+            latencies.extend(view.latency_ms.get(neighbour));
+        }
+    }
+    Graph {
+        nodes: nodes
+            .into_iter()
+            .map(|(id, (collections, roles))| Node {
+                id: id.to_owned(),
+                local: id == local,
+                collections,
+                roles,
+            })
+            .collect(),
+        edges: edges
+            .into_iter()
+            .map(|((from, to), latencies)| Edge {
+                from,
+                to,
+                latency_ms: match latencies.len() {
+                    0 => None,
+                    n => Some(latencies.iter().sum::<f64>() / n as f64),
+                },
+            })
+            .collect(),
+    }
+}
+
+pub fn to_json(local: &str, views: &[TopologyAnnouncement]) -> String {
+    serde_json::to_string_pretty(&build_graph(local, views)).expect("can jsonify topology")
+}
+
+pub fn to_dot(local: &str, views: &[TopologyAnnouncement]) -> String {
+    let graph = build_graph(local, views);
+    let mut dot = String::from("graph peercache {\n");
+    for node in &graph.nodes {
+        let mut label = vec![escape(&node.id)];
+        if !node.collections.is_empty() {
+            label.push(escape(&node.collections.join(", ")));
+        }
+        if !node.roles.is_empty() {
+            label.push(escape(&format!("[{}]", node.roles.join(", "))));
+        }
+        let style = if node.local { ", style=bold" } else { "" };
+        dot.push_str(&format!(
+            "    \"{}\" [label=\"{}\"{}];\n",
+            escape(&node.id),
+            label.join("\\n"),
+            style
+        ));
+    }
+    for edge in &graph.edges {
+        let label = match edge.latency_ms {
+            Some(ms) => format!(" [label=\"{:.1} ms\"]", ms),
+            None => String::new(),
+        };
+        dot.push_str(&format!(
+            "    \"{}\" -- \"{}\"{};\n",
+            escape(&edge.from),
+            escape(&edge.to),
+            label
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+// Peer ids and collection names come from unauthenticated announcements, so
+// everything put between quotes is escaped
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(peer: &str, neighbours: &[&str], latency_ms: &[(&str, f64)]) -> TopologyAnnouncement {
+        TopologyAnnouncement {
+            peer: peer.to_owned(),
+            neighbours: neighbours.iter().map(|n| n.to_string()).collect(),
+            collections: vec!["apes".to_owned()],
+            latency_ms: latency_ms
+                .iter()
+                .map(|(n, ms)| (n.to_string(), *ms))
+                .collect(),
+            roles: vec!["dht-records".to_owned()],
+        }
+    }
+
+    #[test]
+    fn averages_the_latency_reported_by_both_ends() {
+        let views = [
+            view("a", &["b", "c"], &[("b", 10.0)]),
+            view("b", &["a"], &[("a", 20.0)]),
+        ];
+        let graph = build_graph("a", &views);
+        assert_eq!(graph.nodes.len(), 3);
+        assert!(graph.nodes[0].local);
+        assert_eq!(graph.nodes[1].roles, vec!["dht-records"]);
+        assert!(graph.nodes[2].roles.is_empty());
+        assert_eq!(graph.edges.len(), 2);
+        assert_eq!(graph.edges[0].latency_ms, Some(15.0));
+        assert_eq!(graph.edges[1].latency_ms, None);
+
+        let dot = to_dot("a", &views);
+        assert!(dot.contains("\"a\" -- \"b\" [label=\"15.0 ms\"];"));
+        assert!(dot.contains("[label=\"a\\napes\\n[dht-records]\", style=bold]"));
+    }
+
+    #[test]
+    fn escapes_announced_names_in_dot() {
+        let evil = "x\" -- \"y\"; z [shape=box";
+        let dot = to_dot("a", &[view(evil, &["a\\"], &[])]);
+        // Every statement is one node or edge between two quoted ids
+        for line in dot.lines().filter(|l| l.starts_with("    ")) {
+            let unescaped_quotes = line
+                .char_indices()
+                .filter(|(i, c)| *c == '"' && !escaped_at(line, *i))
+                .count();
+            assert_eq!(unescaped_quotes, 4, "{}", line);
+        }
+        assert!(dot.contains("\"a\\\\\" -- \"x\\\" -- \\\"y\\\"; z [shape=box\";"));
+    }
+
+    // Whether the character at i is preceded by an odd number of backslashes
+    fn escaped_at(line: &str, i: usize) -> bool {
+        line[..i].chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+    }
+}
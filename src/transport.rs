@@ -0,0 +1,170 @@
+// The transport stack: TCP and WebSocket-over-TCP (for browser peers dialing
+// /ws addresses), optionally wrapped in a private network (pnet) layer, then
+// authenticated with noise and multiplexed with mplex. Listen and dial
+// addresses pick the layer; /ip4/.../tcp/<port>/ws uses WebSocket.
+use crate::config::ConnectionConfig;
+use crate::Result;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        either::EitherOutput,
+        muxing::StreamMuxerBox,
+        transport::{dummy::DummyTransport, Boxed},
+        upgrade, ConnectedPoint,
+    },
+    identity, mplex,
+    multiaddr::Protocol,
+    noise::{Keypair, NoiseConfig, X25519Spec},
+    pnet::{PnetConfig, PreSharedKey},
+    tcp::TokioTcpConfig,
+    websocket::WsConfig,
+    Multiaddr, PeerId, Transport,
+};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+// Supports no address at all, for nodes without networking
+pub fn isolated() -> Boxed<(PeerId, StreamMuxerBox)> {
+    DummyTransport::new().boxed()
+}
+
+pub fn build(
+    keys: &identity::Keypair,
+    connections: &ConnectionConfig,
+    // Only nodes with the same key can connect
+    swarm_key: Option<PreSharedKey>,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let auth_keys = Keypair::<X25519Spec>::new().into_authentic(keys)?;
+    let per_ip = connections.max_connections_per_ip;
+    let ip_connections = IpConnections::default();
+    // Plain TCP has to come first: it rejects /ws addresses, which then fall through to WebSocket
+    let base = TokioTcpConfig::new().or_transport(WsConfig::new(TokioTcpConfig::new()));
+    Ok(base
+        // Refuse inbound connections from busy addresses before spending a handshake on them
+        .and_then(move |stream, endpoint| {
+            let slot = match &endpoint {
+                ConnectedPoint::Listener { send_back_addr, .. } => {
+                    ip_connections.open(send_back_addr, per_ip)
+                }
+                ConnectedPoint::Dialer { .. } => Ok(None),
+            };
+            async move {
+                slot.map(|slot| Counted {
+                    stream,
+                    _slot: slot,
+                })
+            }
+        })
+        // Encrypts everything, including the noise handshake, so outsiders can't even negotiate
+        .and_then(move |stream, _| async move {
+            match swarm_key {
+                Some(key) => PnetConfig::new(key)
+                    .handshake(stream)
+                    .await
+                    .map(EitherOutput::First),
+                None => Ok(EitherOutput::Second(stream)),
+            }
+        })
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(auth_keys).into_authenticated())
+        .multiplex(mplex::MplexConfig::new())
+        .timeout(connections.negotiation_timeout)
+        .boxed())
+}
+
+// Inbound connections per remote IP, from the moment they are accepted until
+// they fail their handshake or close, so an address at its limit can't keep
+// opening connections that never finish negotiating
+#[derive(Clone, Default)]
+struct IpConnections(Arc<Mutex<HashMap<IpAddr, usize>>>);
+
+impl IpConnections {
+    // Counts a new connection from addr; None for addresses without an IP
+    fn open(&self, addr: &Multiaddr, limit: usize) -> io::Result<Option<IpSlot>> {
+        let ip = match ip_of(addr) {
+            Some(ip) => ip,
+            None => return Ok(None),
+        };
+        let mut counts = self.0.lock().expect("can lock ip connections");
+        let count = counts.get(&ip).copied().unwrap_or(0);
+        if count >= limit {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "too many connections from this address",
+            ));
+        }
+        counts.insert(ip, count + 1);
+        Ok(Some(IpSlot {
+            connections: self.clone(),
+            ip,
+        }))
+    }
+
+    fn release(&self, ip: &IpAddr) {
+        let mut counts = self.0.lock().expect("can lock ip connections");
+        if let Some(count) = counts.get_mut(ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(ip);
+            }
+        }
+    }
+}
+
+// One counted connection; dropped together with the connection's stream
+struct IpSlot {
+    connections: IpConnections,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        self.connections.release(&self.ip);
+    }
+}
+
+// A raw connection stream carrying the slot of its remote IP
+struct Counted<S> {
+    stream: S,
+    _slot: Option<IpSlot>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|p| match p {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
@@ -0,0 +1,176 @@
+// Records removed with DELETE NFT, kept for a while so an accidental delete can
+// be undone with TRASH RESTORE. Saved in the data directory; entries older than
+// the window are dropped for good whenever the trash is loaded or changed.
+use crate::{NFTInfo, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TRASH_FILE: &str = "trash.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trashed {
+    // Seconds since the Unix epoch
+    pub deleted_at: u64,
+    pub record: NFTInfo,
+}
+
+#[derive(Debug, Default)]
+pub struct Trash {
+    entries: Vec<Trashed>,
+    // How long deleted records are kept; zero deletes right away
+    window: Duration,
+    // Where the trash is saved; None (ephemeral nodes) keeps it in memory
+    path: Option<PathBuf>,
+}
+
+impl Trash {
+    pub fn load(data_dir: Option<&Path>, window: Duration) -> Result<Self> {
+        let mut trash = Trash {
+            window,
+            ..Default::default()
+        };
+        if let Some(data_dir) = data_dir {
+            let path = data_dir.join(TRASH_FILE);
+            if path.exists() {
+                trash.entries = serde_json::from_slice(&fs::read(&path)?)
+                    .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+            }
+            trash.path = Some(path);
+        }
+        if trash.purge(now()) {
+            trash.save();
+        }
+        Ok(trash)
+    }
+
+    pub fn put(&mut self, record: NFTInfo) {
+        self.put_at(record, now());
+    }
+
+    fn put_at(&mut self, record: NFTInfo, deleted_at: u64) {
+        self.purge(deleted_at);
+        if !self.window.is_zero() {
+            // Deleting a record again (after recreating it) keeps only the last copy
+            self.entries.retain(|t| !same_record(&t.record, &record));
+            self.entries.push(Trashed { deleted_at, record });
+        }
+        self.save();
+    }
+
+    pub fn list(&mut self) -> &[Trashed] {
+        if self.purge(now()) {
+            self.save();
+        }
+        &self.entries
+    }
+
+    // Takes a record out of the trash to put it back into the store
+    pub fn restore(&mut self, collection_name: &str, item_id: u32) -> Result<NFTInfo> {
+        self.purge(now());
+        let index = self
+            .entries
+            .iter()
+            .position(|t| {
+                t.record.collection_name == collection_name && t.record.item_id == item_id
+            })
+            .ok_or_else(|| format!("{}|{} is not in the trash", collection_name, item_id))?;
+        let restored = self.entries.remove(index);
+        self.save();
+        Ok(restored.record)
+    }
+
+    // Drops the entries older than the window; returns whether any were dropped
+    fn purge(&mut self, now: u64) -> bool {
+        let before = self.entries.len();
+        let window = self.window.as_secs();
+        self.entries
+            .retain(|t| now.saturating_sub(t.deleted_at) < window);
+        self.entries.len() != before
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write(path, &self.entries) {
+                error!("error saving the trash to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn same_record(a: &NFTInfo, b: &NFTInfo) -> bool {
+    a.collection_name == b.collection_name && a.item_id == b.item_id
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn write(path: &Path, entries: &[Trashed]) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(entries)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn nft(item_id: u32) -> NFTInfo {
+        NFTInfo {
+            collection_name: "apes".to_owned(),
+            item_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn restores_deleted_records() {
+        let mut trash = Trash::load(None, DAY).unwrap();
+        trash.put(nft(1));
+        trash.put(nft(2));
+        assert_eq!(trash.list().len(), 2);
+        assert_eq!(trash.restore("apes", 1).unwrap(), nft(1));
+        assert!(trash.restore("apes", 1).is_err());
+        assert_eq!(trash.list().len(), 1);
+    }
+
+    #[test]
+    fn drops_records_after_the_window() {
+        let mut trash = Trash::load(None, DAY).unwrap();
+        let now = now();
+        trash.put_at(nft(1), now - DAY.as_secs());
+        trash.put_at(nft(2), now - DAY.as_secs() + 60);
+        assert_eq!(trash.list().len(), 1);
+        assert!(trash.restore("apes", 1).is_err());
+
+        // Without a window nothing is kept
+        let mut trash = Trash::load(None, Duration::ZERO).unwrap();
+        trash.put(nft(1));
+        assert!(trash.list().is_empty());
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let dir = std::env::temp_dir().join(format!("peercache-trash-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut trash = Trash::load(Some(&dir), DAY).unwrap();
+        trash.put(nft(1));
+        trash.put(nft(2));
+        trash.restore("apes", 2).unwrap();
+
+        let mut reloaded = Trash::load(Some(&dir), DAY).unwrap();
+        let entries = reloaded.list();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].record, nft(1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+// Version skew between this node and its peers, from what they report over
+// identify: newer peers may send fields this version ignores, and peers on
+// another protocol version can't be queried at all.
+use libp2p::identify::IdentifyInfo;
+
+const AGENT_PREFIX: &str = "peercache/";
+
+pub fn agent_version() -> String {
+    format!("{}{}", AGENT_PREFIX, env!("CARGO_PKG_VERSION"))
+}
+
+// Parses the <major>.<minor>.<patch> of a peercache/<version> agent version
+pub fn parse(agent_version: &str) -> Option<(u64, u64, u64)> {
+    let version = agent_version.strip_prefix(AGENT_PREFIX)?;
+    // Drop pre-release and build suffixes, e.g. 0.2.0-beta.1
+    let version = version.split(|c| c == '-' || c == '+').next()?;
+    let mut parts = version.split('.').map(|p| p.parse::<u64>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) => {
+            Some((major, minor, patch))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct VersionSkew {
+    // Peers running peercache that reported a version we can read
+    pub identified: usize,
+    pub newer: usize,
+    pub newest: Option<String>,
+    // Peers speaking another protocol version, whose queries fail
+    pub incompatible: usize,
+}
+
+impl VersionSkew {
+    pub fn new<'a>(
+        local_agent: &str,
+        local_protocol: &str,
+        peers: impl Iterator<Item = &'a IdentifyInfo>,
+    ) -> Self {
+        let local = parse(local_agent);
+        let mut skew = VersionSkew::default();
+        let mut newest = local;
+        for info in peers {
+            let version = match parse(&info.agent_version) {
+                Some(version) => version,
+                None => continue,
+            };
+            skew.identified += 1;
+            if info.protocol_version != local_protocol {
+                skew.incompatible += 1;
+            }
+            if local.map_or(false, |local| version > local) {
+                skew.newer += 1;
+                if newest.map_or(true, |newest| version > newest) {
+                    newest = Some(version);
+                    skew.newest = Some(info.agent_version.clone());
+                }
+            }
+        }
+        skew
+    }
+
+    // Most of the identified peers run a newer version
+    pub fn outdated(&self) -> bool {
+        self.newer * 2 > self.identified
+    }
+
+    pub fn advisory(&self) -> Option<String> {
+        if !self.outdated() && self.incompatible == 0 {
+            return None;
+        }
+        let mut advisory = Vec::new();
+        if self.outdated() {
+            advisory.push(format!(
+                "{} of {} peers run a newer version (up to {}), upgrade this node: fields added since {} are ignored in their responses and announcements",
+                self.newer,
+                self.identified,
+                self.newest.as_deref().unwrap_or("unknown"),
+                agent_version(),
+            ));
+        }
+        if self.incompatible > 0 {
+            advisory.push(format!(
+                "{} of {} peers speak another protocol version, queries to them fail",
+                self.incompatible, self.identified
+            ));
+        }
+        Some(advisory.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    const PROTOCOL: &str = "/peercache/1.0.0";
+
+    fn info(agent_version: &str, protocol_version: &str) -> IdentifyInfo {
+        IdentifyInfo {
+            public_key: Keypair::generate_ed25519().public(),
+            protocol_version: protocol_version.to_owned(),
+            agent_version: agent_version.to_owned(),
+            listen_addrs: Vec::new(),
+            protocols: Vec::new(),
+            observed_addr: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn parses_agent_versions() {
+        assert_eq!(parse("peercache/0.1.0"), Some((0, 1, 0)));
+        assert_eq!(parse("peercache/1.12.3-beta.1"), Some((1, 12, 3)));
+        assert_eq!(parse("peercache/1.2"), None);
+        assert_eq!(parse("peercache/1.2.3.4"), None);
+        assert_eq!(parse("rust-libp2p/0.39.0"), None);
+        assert!(parse(&agent_version()).is_some());
+    }
+
+    #[test]
+    fn advises_when_most_peers_are_newer() {
+        let peers = [
+            info("peercache/0.2.0", PROTOCOL),
+            info("peercache/0.3.1", PROTOCOL),
+            info("peercache/0.1.0", PROTOCOL),
+            info("other/9.9.9", PROTOCOL),
+        ];
+        let skew = VersionSkew::new("peercache/0.1.0", PROTOCOL, peers.iter());
+        assert_eq!(skew.identified, 3);
+        assert_eq!(skew.newer, 2);
+        assert_eq!(skew.newest.as_deref(), Some("peercache/0.3.1"));
+        assert!(skew.outdated());
+        assert!(skew.advisory().unwrap().contains("2 of 3 peers"));
+
+        let skew = VersionSkew::new("peercache/0.3.1", PROTOCOL, peers.iter());
+        assert_eq!(skew.newer, 0);
+        assert_eq!(skew.advisory(), None);
+    }
+
+    #[test]
+    fn advises_about_incompatible_protocols() {
+        let peers = [
+            info("peercache/0.1.0", PROTOCOL),
+            info("peercache/1.0.0", "/peercache/2.0.0"),
+            info("peercache/0.1.0", PROTOCOL),
+        ];
+        let skew = VersionSkew::new("peercache/0.1.0", PROTOCOL, peers.iter());
+        assert!(!skew.outdated());
+        assert_eq!(skew.incompatible, 1);
+        assert!(skew
+            .advisory()
+            .unwrap()
+            .starts_with("1 of 3 peers speak another protocol version"));
+    }
+}